@@ -1,9 +1,244 @@
+use std::convert::{Infallible, TryFrom};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use service::ServiceState;
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use service::codec::{PubAckReasonCode, PublishProperties, Qos};
+use service::{Message, PublishOutcome, RemoteAddr, ServiceState, SubscriptionInfo};
+
+use crate::reload::Reloader;
+use warp::http::StatusCode;
+use warp::path::Tail;
 use warp::reply::Response;
 use warp::{Filter, Rejection, Reply};
 
+#[derive(Serialize)]
+struct Subscription {
+    client_id: String,
+    filter: String,
+    qos: u8,
+    no_local: bool,
+    retain_as_published: bool,
+    retain_handling: u8,
+    id: Option<usize>,
+}
+
+impl From<SubscriptionInfo> for Subscription {
+    fn from(info: SubscriptionInfo) -> Self {
+        Self {
+            client_id: info.client_id,
+            filter: info.filter,
+            qos: info.qos.into(),
+            no_local: info.no_local,
+            retain_as_published: info.retain_as_published,
+            retain_handling: info.retain_handling.into(),
+            id: info.id.map(|id| id.get()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RetainedMessage {
+    topic: String,
+    qos: u8,
+    /// The payload, invalid UTF-8 sequences are replaced with `U+FFFD`.
+    payload: String,
+    payload_size: usize,
+}
+
+impl From<Message> for RetainedMessage {
+    fn from(msg: Message) -> Self {
+        Self {
+            topic: msg.topic().to_string(),
+            qos: msg.qos().into(),
+            payload: String::from_utf8_lossy(msg.payload()).into_owned(),
+            payload_size: msg.payload().len(),
+        }
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum PayloadEncoding {
+    #[default]
+    Raw,
+    Base64,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PublishRequestProperties {
+    payload_format_indicator: Option<bool>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    /// Base64 encoded correlation data.
+    correlation_data: Option<String>,
+    user_properties: Vec<(String, String)>,
+}
+
+#[derive(Deserialize)]
+pub struct PublishRequest {
+    topic: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    payload_encoding: PayloadEncoding,
+    #[serde(default)]
+    properties: PublishRequestProperties,
+}
+
+impl PublishRequest {
+    /// Creates a request with a raw payload and no properties.
+    pub fn new(topic: String, qos: u8, retain: bool, payload: String) -> Self {
+        Self {
+            topic,
+            qos,
+            retain,
+            payload,
+            payload_encoding: PayloadEncoding::Raw,
+            properties: PublishRequestProperties::default(),
+        }
+    }
+
+    pub fn into_message(self) -> Result<Message, &'static str> {
+        if self.topic.starts_with('$') || !service::valid_topic(&self.topic) {
+            return Err("invalid topic");
+        }
+        let qos = Qos::try_from(self.qos).map_err(|_| "invalid qos")?;
+        let payload = match self.payload_encoding {
+            PayloadEncoding::Raw => Bytes::from(self.payload),
+            PayloadEncoding::Base64 => base64::decode(&self.payload)
+                .map_err(|_| "invalid base64 payload")?
+                .into(),
+        };
+
+        let properties = self.properties;
+        if properties.payload_format_indicator.unwrap_or_default()
+            && std::str::from_utf8(&payload).is_err()
+        {
+            return Err("payload is not valid UTF-8");
+        }
+        if matches!(&properties.response_topic, Some(topic) if !service::valid_topic(topic)) {
+            return Err("invalid response topic");
+        }
+        let correlation_data = match properties.correlation_data {
+            Some(data) => Some(
+                base64::decode(&data)
+                    .map_err(|_| "invalid base64 correlation data")?
+                    .into(),
+            ),
+            None => None,
+        };
+
+        Ok(Message::new(self.topic, qos, payload)
+            .with_retain(self.retain)
+            .with_properties(PublishProperties {
+                payload_format_indicator: properties.payload_format_indicator,
+                message_expiry_interval: properties.message_expiry_interval,
+                content_type: properties.content_type.map(Into::into),
+                response_topic: properties.response_topic.map(Into::into),
+                correlation_data,
+                user_properties: properties
+                    .user_properties
+                    .into_iter()
+                    .map(|(key, value)| (key.into(), value.into()))
+                    .collect(),
+                ..PublishProperties::default()
+            }))
+    }
+}
+
+/// Parses the credentials of the HTTP basic authentication scheme.
+pub fn parse_basic_auth(authorization: &str) -> Option<(String, String)> {
+    let data = authorization.strip_prefix("Basic ")?;
+    let data = String::from_utf8(base64::decode(data.trim()).ok()?).ok()?;
+    let (username, password) = data.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Authenticates the caller with the plugins using the HTTP basic authentication scheme, returns
+/// the uid or the error response.
+pub async fn authenticate(
+    state: &ServiceState,
+    authorization: Option<&str>,
+) -> Result<String, Response> {
+    let unauthorized = || {
+        warp::reply::with_header(
+            StatusCode::UNAUTHORIZED,
+            "www-authenticate",
+            "Basic realm=\"rsmqttd\"",
+        )
+        .into_response()
+    };
+    let (username, password) = authorization
+        .and_then(parse_basic_auth)
+        .ok_or_else(unauthorized)?;
+    match state.auth(&username, &password).await {
+        Ok(Some(uid)) => Ok(uid),
+        Ok(None) => Err(unauthorized()),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
+/// Checks that the caller is authenticated as one of `admin_users`.
+pub async fn authorize_admin(
+    state: &ServiceState,
+    admin_users: &[String],
+    authorization: Option<&str>,
+) -> Result<(), Response> {
+    if admin_users.is_empty() {
+        return Err(warp::reply::with_status(
+            "no admin users are configured",
+            StatusCode::FORBIDDEN,
+        )
+        .into_response());
+    }
+    let uid = authenticate(state, authorization).await?;
+    if !admin_users.contains(&uid) {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    Ok(())
+}
+
+/// Extracts the result of [`authorize_admin`].
+fn admin(
+    state: Arc<ServiceState>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Result<(), Response>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(
+        move |authorization: Option<String>| {
+            let state = state.clone();
+            let admin_users = admin_users.clone();
+            async move {
+                Ok::<_, Rejection>(
+                    authorize_admin(&state, &admin_users, authorization.as_deref()).await,
+                )
+            }
+        },
+    )
+}
+
+fn with_state(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Arc<ServiceState>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+pub fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+fn not_found() -> Response {
+    StatusCode::NOT_FOUND.into_response()
+}
+
 pub fn metrics(
     state: Arc<ServiceState>,
 ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
@@ -14,3 +249,589 @@ pub fn metrics(
             warp::reply::json(&metrics).into_response()
         })
 }
+
+/// `POST publish`
+///
+/// The caller is authenticated with the plugins using the HTTP basic authentication scheme, the
+/// request is rejected with `401 Unauthorized` if the `Authorization` header is missing.
+pub fn publish(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("publish")
+        .and(warp::post())
+        .and(with_state(state))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json())
+        .and_then(
+            |state: Arc<ServiceState>,
+             addr: Option<SocketAddr>,
+             authorization: Option<String>,
+             req: PublishRequest| async move {
+                Ok::<_, Infallible>(do_publish(state, addr, authorization, req).await)
+            },
+        )
+}
+
+async fn do_publish(
+    state: Arc<ServiceState>,
+    addr: Option<SocketAddr>,
+    authorization: Option<String>,
+    req: PublishRequest,
+) -> Response {
+    let uid = match authenticate(&state, authorization.as_deref()).await {
+        Ok(uid) => uid,
+        Err(resp) => return resp,
+    };
+
+    let msg = match req.into_message() {
+        Ok(msg) => msg,
+        Err(err) => return warp::reply::with_status(err, StatusCode::BAD_REQUEST).into_response(),
+    };
+    match publish_message(&state, addr, Some(&uid), msg).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err((status, reason)) => warp::reply::with_status(reason, status).into_response(),
+    }
+}
+
+/// Publishes a message of the REST or the GraphQL API on behalf of `uid`.
+///
+/// It goes through the same checks and hooks as the messages of the clients. The error contains
+/// the status code and the reason of the refusal.
+pub async fn publish_message(
+    state: &ServiceState,
+    addr: Option<SocketAddr>,
+    uid: Option<&str>,
+    msg: Message,
+) -> Result<(), (StatusCode, String)> {
+    let remote_addr = RemoteAddr {
+        listener: "http".into(),
+        protocol: "http".into(),
+        addr: addr.map(|addr| addr.to_string().into()),
+        mountpoint: None,
+        cert_identity: None,
+        peer_cred: None,
+        uid: None,
+    };
+
+    if msg.is_retain() && !state.config().retain_available {
+        return Err((StatusCode::BAD_REQUEST, "retain not supported".to_string()));
+    }
+
+    match state.publish_as(&remote_addr, uid, msg).await {
+        Ok(PublishOutcome::Accepted(_)) => Ok(()),
+        Ok(PublishOutcome::QosNotSupported) => {
+            Err((StatusCode::BAD_REQUEST, "qos not supported".to_string()))
+        }
+        Ok(PublishOutcome::NotAuthorized)
+        | Ok(PublishOutcome::Unrouted(PubAckReasonCode::NotAuthorized)) => {
+            Err((StatusCode::FORBIDDEN, "not authorized".to_string()))
+        }
+        // dropped by a plugin
+        Ok(PublishOutcome::Unrouted(reason_code)) if reason_code.is_success() => Ok(()),
+        Ok(PublishOutcome::Unrouted(reason_code)) => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("rejected: {:?}", reason_code),
+        )),
+        Err(_) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error".to_string(),
+        )),
+    }
+}
+
+/// `GET sessions`
+pub fn sessions(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("sessions")
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: Arc<ServiceState>| {
+            let mut sessions = state.sessions();
+            sessions.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+            warp::reply::json(&sessions).into_response()
+        })
+}
+
+/// `GET sessions/{client_id}`
+pub fn session(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("sessions" / String)
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|client_id: String, state: Arc<ServiceState>| {
+            match state.session(&decode(&client_id)) {
+                Some(session) => warp::reply::json(&session).into_response(),
+                None => not_found(),
+            }
+        })
+}
+
+/// `GET sessions/{client_id}/subscriptions`
+pub fn session_subscriptions(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("sessions" / String / "subscriptions")
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|client_id: String, state: Arc<ServiceState>| {
+            let client_id = decode(&client_id);
+            if state.session(&client_id).is_none() {
+                return not_found();
+            }
+            let mut subscriptions = state.client_subscriptions(&client_id);
+            subscriptions.sort_by(|a, b| a.filter.cmp(&b.filter));
+            let subscriptions = subscriptions
+                .into_iter()
+                .map(Subscription::from)
+                .collect::<Vec<_>>();
+            warp::reply::json(&subscriptions).into_response()
+        })
+}
+
+/// `POST sessions/{client_id}/disconnect`
+///
+/// Disconnects a client and keeps its session, the caller must be an admin user.
+pub fn disconnect_session(
+    state: Arc<ServiceState>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("sessions" / String / "disconnect")
+        .and(warp::post())
+        .and(admin(state.clone(), admin_users))
+        .and(with_state(state))
+        .and_then(
+            |client_id: String, admin: Result<(), Response>, state: Arc<ServiceState>| async move {
+                if let Err(resp) = admin {
+                    return Ok::<_, Infallible>(resp);
+                }
+                Ok(if state.kick(&decode(&client_id)).await {
+                    StatusCode::NO_CONTENT.into_response()
+                } else {
+                    not_found()
+                })
+            },
+        )
+}
+
+/// `DELETE sessions/{client_id}`
+///
+/// Removes a session, the client is disconnected if it is connected. The caller must be an admin
+/// user.
+pub fn remove_session(
+    state: Arc<ServiceState>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("sessions" / String)
+        .and(warp::delete())
+        .and(admin(state.clone(), admin_users))
+        .and(with_state(state))
+        .and_then(
+            |client_id: String, admin: Result<(), Response>, state: Arc<ServiceState>| async move {
+                if let Err(resp) = admin {
+                    return Ok::<_, Infallible>(resp);
+                }
+                Ok(if state.remove_session(&decode(&client_id)).await {
+                    StatusCode::NO_CONTENT.into_response()
+                } else {
+                    not_found()
+                })
+            },
+        )
+}
+
+/// `GET subscriptions`
+pub fn subscriptions(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("subscriptions")
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: Arc<ServiceState>| {
+            let mut subscriptions = state.subscriptions();
+            subscriptions.sort_by(|a, b| (&a.client_id, &a.filter).cmp(&(&b.client_id, &b.filter)));
+            let subscriptions = subscriptions
+                .into_iter()
+                .map(Subscription::from)
+                .collect::<Vec<_>>();
+            warp::reply::json(&subscriptions).into_response()
+        })
+}
+
+/// `GET retained`
+pub fn retained_messages(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("retained")
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: Arc<ServiceState>| {
+            let mut msgs = state.retained_messages();
+            msgs.sort_by(|a, b| a.topic().cmp(b.topic()));
+            let msgs = msgs
+                .into_iter()
+                .map(RetainedMessage::from)
+                .collect::<Vec<_>>();
+            warp::reply::json(&msgs).into_response()
+        })
+}
+
+/// `GET retained/{topic}`
+///
+/// The topic may contain `/`, other reserved characters must be percent-encoded.
+pub fn retained_message(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("retained" / ..)
+        .and(warp::path::tail())
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|topic: Tail, state: Arc<ServiceState>| {
+            match state.retained_message(&decode(topic.as_str())) {
+                Some(msg) => warp::reply::json(&RetainedMessage::from(msg)).into_response(),
+                None => not_found(),
+            }
+        })
+}
+
+/// `DELETE retained/{topic}`
+///
+/// The caller must be an admin user.
+pub fn remove_retained_message(
+    state: Arc<ServiceState>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("retained" / ..)
+        .and(warp::path::tail())
+        .and(warp::delete())
+        .and(admin(state.clone(), admin_users))
+        .and(with_state(state))
+        .map(
+            |topic: Tail, admin: Result<(), Response>, state: Arc<ServiceState>| {
+                if let Err(resp) = admin {
+                    return resp;
+                }
+                if state.remove_retained_message(&decode(topic.as_str())) {
+                    StatusCode::NO_CONTENT.into_response()
+                } else {
+                    not_found()
+                }
+            },
+        )
+}
+
+/// `POST reload`
+///
+/// Reloads the service config and the plugins from the config file, the caller must be an admin
+/// user.
+pub fn reload(
+    state: Arc<ServiceState>,
+    reloader: Arc<Reloader>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("reload")
+        .and(warp::post())
+        .and(admin(state, admin_users))
+        .and(warp::any().map(move || reloader.clone()))
+        .and_then(
+            |admin: Result<(), Response>, reloader: Arc<Reloader>| async move {
+                if let Err(resp) = admin {
+                    return Ok::<_, Infallible>(resp);
+                }
+                Ok(match reloader.reload().await {
+                    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                    Err(err) => warp::reply::with_status(
+                        format!("{:#}", err),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into_response(),
+                })
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use service::codec::{Codec, ConnAck, DisconnectReasonCode, Packet};
+    use service::plugin::{Plugin, PluginResult};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+    use crate::config::Config;
+
+    struct AuthPlugin;
+
+    #[async_trait::async_trait]
+    impl Plugin for AuthPlugin {
+        async fn auth(&self, user: &str, password: &str) -> PluginResult<Option<String>> {
+            Ok(
+                matches!((user, password), ("admin", "secret") | ("sunli", "abcdef"))
+                    .then(|| user.to_string()),
+            )
+        }
+    }
+
+    fn create_state() -> Arc<ServiceState> {
+        ServiceState::new(
+            serde_yaml::from_str("{}").unwrap(),
+            vec![("auth", Arc::new(AuthPlugin) as Arc<dyn Plugin>)],
+        )
+        .unwrap()
+    }
+
+    fn basic_auth(username: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        )
+    }
+
+    async fn retain(state: &ServiceState, topic: &str) {
+        let remote_addr = RemoteAddr {
+            listener: "http".into(),
+            protocol: "http".into(),
+            addr: None,
+            mountpoint: None,
+            cert_identity: None,
+            peer_cred: None,
+            uid: None,
+        };
+        let msg = Message::new(topic.to_string(), Qos::AtMostOnce, "1").with_retain(true);
+        state.publish_as(&remote_addr, None, msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints() {
+        let state = create_state();
+        retain(&state, "a").await;
+        let admin_users: Arc<[String]> = vec!["admin".to_string()].into();
+        let filter = remove_retained_message(state.clone(), admin_users.clone())
+            .or(disconnect_session(state.clone(), admin_users.clone()))
+            .unify()
+            .or(remove_session(state.clone(), admin_users))
+            .unify();
+        let request = |method: &str, path: &str, authorization: Option<String>| {
+            let mut request = warp::test::request().method(method).path(path);
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request
+        };
+
+        for (method, path) in &[
+            ("DELETE", "/retained/a"),
+            ("POST", "/sessions/c1/disconnect"),
+            ("DELETE", "/sessions/c1"),
+        ] {
+            let resp = request(method, path, None).reply(&filter).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert!(resp.headers().contains_key("www-authenticate"));
+
+            let resp = request(method, path, Some(basic_auth("admin", "wrong")))
+                .reply(&filter)
+                .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+            let resp = request(method, path, Some(basic_auth("sunli", "abcdef")))
+                .reply(&filter)
+                .await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(state.retained_message("a").is_some());
+
+        let resp = request("DELETE", "/retained/a", Some(basic_auth("admin", "secret")))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(state.retained_message("a").is_none());
+
+        let resp = request(
+            "DELETE",
+            "/sessions/c1",
+            Some(basic_auth("admin", "secret")),
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_disabled() {
+        let state = create_state();
+        retain(&state, "a").await;
+        let filter = remove_retained_message(state.clone(), Vec::new().into());
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path("/retained/a")
+            .header("authorization", basic_auth("admin", "secret"))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(state.retained_message("a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let state = create_state();
+        let filter = publish(state.clone());
+        let request = || {
+            warp::test::request().method("POST").path("/publish").json(
+                &serde_yaml::from_str::<serde_yaml::Value>(
+                    r#"{"topic": "a/b", "retain": true, "payload": "1"}"#,
+                )
+                .unwrap(),
+            )
+        };
+
+        let resp = request().reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key("www-authenticate"));
+        assert!(state.retained_message("a/b").is_none());
+
+        let resp = request()
+            .header("authorization", basic_auth("sunli", "wrong"))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.retained_message("a/b").is_none());
+
+        let resp = request()
+            .header("authorization", basic_auth("sunli", "abcdef"))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(state.retained_message("a/b").is_some());
+    }
+
+    type MemoryCodec = Codec<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    fn packet(yaml: &str) -> Packet {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    async fn recv(codec: &mut MemoryCodec) -> Packet {
+        tokio::time::timeout(Duration::from_secs(3), codec.decode())
+            .await
+            .expect("receive packet")
+            .unwrap()
+            .expect("unexpected eof")
+            .0
+    }
+
+    /// Connects a MQTT 5 client, returns the CONNACK packet.
+    async fn connect(state: &Arc<ServiceState>, client_id: &str) -> (MemoryCodec, ConnAck) {
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let remote_addr = RemoteAddr {
+            listener: "memory".into(),
+            protocol: "memory".into(),
+            addr: None,
+            mountpoint: None,
+            cert_identity: None,
+            peer_cred: None,
+            uid: None,
+        };
+        tokio::spawn(service::client_loop(
+            state.clone(),
+            server_reader,
+            server_writer,
+            remote_addr,
+        ));
+
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let mut codec = Codec::new(client_reader, client_writer);
+        codec
+            .encode(&packet(&format!(
+                "{{type: connect, level: V5, client_id: {}}}",
+                client_id
+            )))
+            .await
+            .unwrap();
+        match recv(&mut codec).await {
+            Packet::ConnAck(connack) => (codec, connack),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let config_filename =
+            std::env::temp_dir().join(format!("rsmqttd-reload-{}.yaml", std::process::id()));
+        let config = "service: {maximum_qos: AtLeastOnce, max_topic_alias: 32}";
+        std::fs::write(&config_filename, config).unwrap();
+        let state = ServiceState::new(
+            Config::load(&config_filename).unwrap().service,
+            vec![("auth", Arc::new(AuthPlugin) as Arc<dyn Plugin>)],
+        )
+        .unwrap();
+        let reloader = Arc::new(Reloader::new(state.clone(), Some(config_filename.clone())));
+        let filter = reload(state.clone(), reloader, vec!["admin".to_string()].into());
+
+        let (mut a, connack) = connect(&state, "a").await;
+        assert_eq!(connack.properties.maximum_qos, Some(Qos::AtLeastOnce));
+        assert_eq!(connack.properties.topic_alias_max, Some(32));
+
+        std::fs::write(
+            &config_filename,
+            "service: {maximum_qos: AtMostOnce, max_topic_alias: 4}",
+        )
+        .unwrap();
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/reload")
+            .header("authorization", basic_auth("admin", "secret"))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        std::fs::remove_file(&config_filename).unwrap();
+
+        // the existing connection keeps the values announced in its CONNACK
+        a.encode(&packet(
+            r#"
+            type: publish
+            packet_id: 1
+            qos: AtLeastOnce
+            topic: test
+            payload: "1"
+            properties:
+              topic_alias: 10
+            "#,
+        ))
+        .await
+        .unwrap();
+        match recv(&mut a).await {
+            Packet::PubAck(puback) => assert_eq!(puback.reason_code, PubAckReasonCode::Success),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+
+        // the new connections use the reloaded values
+        let (mut b, connack) = connect(&state, "b").await;
+        assert_eq!(connack.properties.maximum_qos, Some(Qos::AtMostOnce));
+        assert_eq!(connack.properties.topic_alias_max, Some(4));
+        b.encode(&packet(
+            r#"
+            type: publish
+            packet_id: 1
+            qos: AtLeastOnce
+            topic: test
+            payload: "1"
+            "#,
+        ))
+        .await
+        .unwrap();
+        match recv(&mut b).await {
+            Packet::Disconnect(disconnect) => {
+                assert_eq!(
+                    disconnect.reason_code,
+                    DisconnectReasonCode::QoSNotSupported
+                )
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+}
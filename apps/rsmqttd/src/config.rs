@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
 use serde::Deserialize;
 use serde_yaml::Value;
 use service::ServiceConfig;
@@ -14,12 +17,71 @@ pub struct Config {
     pub plugins: Vec<Value>,
 }
 
+impl Config {
+    pub fn load(filename: &Path) -> Result<Self> {
+        serde_yaml::from_str::<Config>(
+            &std::fs::read_to_string(filename)
+                .with_context(|| format!("load config file '{}'.", filename.display()))?,
+        )
+        .with_context(|| format!("parse config file '{}'.", filename.display()))
+    }
+}
+
+/// The certificate files of TCP and QUIC listeners are reloaded when they change on disk, the
+/// WebSocket and HTTP listeners load them once at startup.
 #[derive(Debug, Deserialize, Clone)]
 pub struct TlsConfig {
+    /// The certificate chain, starting with the end-entity certificate.
+    pub cert: String,
+    /// The private key in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) format.
+    pub key: String,
+    /// The certificates selected by the server name that the clients request.
+    ///
+    /// Only TCP and QUIC listeners support it, it is rejected for the WebSocket listeners and the
+    /// HTTP server.
+    #[serde(default)]
+    pub sni: Vec<SniCertConfig>,
+    /// Requests a certificate from the clients.
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SniCertConfig {
+    /// The server name, it can start with a `*.` wildcard label.
+    pub server_name: String,
     pub cert: String,
     pub key: String,
 }
 
+/// The field of the client certificate used as the identity of the client.
+#[derive(Debug, Deserialize, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CertIdentitySource {
+    /// The common name of the subject.
+    #[default]
+    Cn,
+    /// The first DNS name, email or URI of the subject alternative names.
+    San,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientAuthConfig {
+    /// The CA bundle used to verify the client certificates.
+    pub ca: String,
+    /// Rejects the clients without a certificate.
+    #[serde(default)]
+    pub required: bool,
+    /// The field of the certificate used as the identity of the client, the common name by
+    /// default.
+    ///
+    /// The identity is only available on TCP and QUIC listeners, this option and
+    /// `use_as_username` are rejected for the WebSocket listeners and the HTTP server.
+    pub identity: Option<CertIdentitySource>,
+    /// Authenticates the clients with the identity of their certificate without a password.
+    #[serde(default)]
+    pub use_as_username: bool,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct TcpConfig {
     #[serde(default = "default_host")]
@@ -44,6 +106,12 @@ pub struct HttpConfig {
     pub websocket: bool,
     pub api: bool,
     pub graphql_api: bool,
+    /// The users, authenticated by the plugins with the HTTP basic authentication scheme, that
+    /// can disconnect clients, remove sessions and retained messages and reload the config.
+    ///
+    /// These endpoints are disabled if it is empty.
+    #[serde(default)]
+    pub admin_users: Vec<String>,
 }
 
 impl HttpConfig {
@@ -53,10 +121,95 @@ impl HttpConfig {
     }
 }
 
+#[derive(Debug, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    Tcp,
+    Ws,
+    /// MQTT over the first bidirectional stream of a QUIC connection, the clients must use the
+    /// `mqtt` ALPN protocol.
+    Quic,
+    Unix,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub protocol: ListenerProtocol,
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// The port of TCP, WebSocket and QUIC listeners.
+    pub port: Option<u16>,
+    /// The socket path of Unix listeners.
+    pub path: Option<String>,
+    /// The file permissions of the socket of Unix listeners in octal, e.g. `"660"`.
+    pub permissions: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// The maximum number of concurrent connections, unlimited if not specified.
+    pub max_connections: Option<usize>,
+    /// The prefix added to the topics of the clients connected to this listener.
+    pub mountpoint: Option<String>,
+    /// Reads the address of the clients from the PROXY protocol v1/v2 header sent by a load
+    /// balancer, only TCP listeners support it.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// The options of WebSocket listeners.
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebSocketConfig {
+    /// The path of the upgrade requests.
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    /// The values of the `Origin` header that browsers are allowed to send, all origins are
+    /// allowed if it is empty.
+    ///
+    /// The requests without the header are always allowed since only browsers send it.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Authenticates the clients with the credentials of the upgrade request.
+    pub credentials: Option<WebSocketCredentialsConfig>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            path: default_websocket_path(),
+            allowed_origins: Vec::new(),
+            credentials: None,
+        }
+    }
+}
+
+/// The sources of the credentials in the upgrade request, they are checked in the order of the
+/// fields.
+///
+/// The clients that send credentials are authenticated before the CONNECT packet, and the login
+/// of the CONNECT packet is ignored.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebSocketCredentialsConfig {
+    /// Reads the `Authorization` header of the HTTP basic authentication scheme.
+    #[serde(default)]
+    pub basic_auth: bool,
+    pub username_header: Option<String>,
+    /// The password is empty if it is not specified or the request does not contain it.
+    pub password_header: Option<String>,
+    pub username_cookie: Option<String>,
+    /// The password is empty if it is not specified or the request does not contain it.
+    pub password_cookie: Option<String>,
+    /// Rejects the upgrade requests without credentials.
+    #[serde(default)]
+    pub required: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NetworkConfig {
     pub tcp: Option<TcpConfig>,
     pub http: Option<HttpConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
 }
 
 impl Default for NetworkConfig {
@@ -74,7 +227,9 @@ impl Default for NetworkConfig {
                 websocket: true,
                 api: true,
                 graphql_api: true,
+                admin_users: Vec::new(),
             }),
+            listeners: Vec::new(),
         }
     }
 }
@@ -82,3 +237,7 @@ impl Default for NetworkConfig {
 fn default_host() -> String {
     "127.0.0.1".to_string()
 }
+
+fn default_websocket_path() -> String {
+    "/ws".to_string()
+}
@@ -0,0 +1,307 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_graphql::{Context, Error, Object, Result, Schema, Subscription};
+use service::{Message, Metrics, ServiceState, SessionInfo, SubscriptionInfo};
+use tokio_stream::Stream;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::api::PublishRequest;
+
+type AdminSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// The HTTP request of a query or a mutation, it is missing for the operations sent over the
+/// WebSocket of the subscriptions.
+struct Caller {
+    addr: Option<SocketAddr>,
+    authorization: Option<String>,
+}
+
+/// The users that can call the mutations that change the state.
+struct AdminUsers(Arc<[String]>);
+
+fn caller<'a>(ctx: &'a Context<'_>) -> (Option<SocketAddr>, Option<&'a str>) {
+    match ctx.data_opt::<Caller>() {
+        Some(caller) => (caller.addr, caller.authorization.as_deref()),
+        None => (None, None),
+    }
+}
+
+/// Converts an error response of the REST API to a GraphQL error.
+fn response_error(resp: Response) -> Error {
+    Error::new(
+        resp.status()
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase(),
+    )
+}
+
+struct ClientSubscription(SubscriptionInfo);
+
+#[Object]
+impl ClientSubscription {
+    async fn client_id(&self) -> &str {
+        &self.0.client_id
+    }
+
+    async fn filter(&self) -> &str {
+        &self.0.filter
+    }
+
+    async fn qos(&self) -> u8 {
+        self.0.qos.into()
+    }
+
+    async fn no_local(&self) -> bool {
+        self.0.no_local
+    }
+
+    async fn retain_as_published(&self) -> bool {
+        self.0.retain_as_published
+    }
+
+    async fn retain_handling(&self) -> u8 {
+        self.0.retain_handling.into()
+    }
+
+    async fn id(&self) -> Option<usize> {
+        self.0.id.map(|id| id.get())
+    }
+}
+
+struct RetainedMessage(Message);
+
+#[Object]
+impl RetainedMessage {
+    async fn topic(&self) -> &str {
+        self.0.topic()
+    }
+
+    async fn qos(&self) -> u8 {
+        self.0.qos().into()
+    }
+
+    /// The payload, invalid UTF-8 sequences are replaced with `U+FFFD`.
+    async fn payload(&self) -> String {
+        String::from_utf8_lossy(self.0.payload()).into_owned()
+    }
+
+    async fn payload_size(&self) -> usize {
+        self.0.payload().len()
+    }
+}
+
+struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn metrics(&self, ctx: &Context<'_>) -> Metrics {
+        ctx.data_unchecked::<Arc<ServiceState>>().metrics()
+    }
+
+    async fn sessions(&self, ctx: &Context<'_>) -> Vec<SessionInfo> {
+        let mut sessions = ctx.data_unchecked::<Arc<ServiceState>>().sessions();
+        sessions.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        sessions
+    }
+
+    /// Returns the subscriptions, or only the subscriptions of `client_id` if specified.
+    async fn subscriptions(
+        &self,
+        ctx: &Context<'_>,
+        client_id: Option<String>,
+    ) -> Vec<ClientSubscription> {
+        let mut subscriptions = ctx.data_unchecked::<Arc<ServiceState>>().subscriptions();
+        if let Some(client_id) = client_id {
+            subscriptions.retain(|subscription| subscription.client_id == client_id);
+        }
+        subscriptions.sort_by(|a, b| (&a.client_id, &a.filter).cmp(&(&b.client_id, &b.filter)));
+        subscriptions.into_iter().map(ClientSubscription).collect()
+    }
+
+    async fn retained_messages(&self, ctx: &Context<'_>) -> Vec<RetainedMessage> {
+        let mut msgs = ctx
+            .data_unchecked::<Arc<ServiceState>>()
+            .retained_messages();
+        msgs.sort_by(|a, b| a.topic().cmp(b.topic()));
+        msgs.into_iter().map(RetainedMessage).collect()
+    }
+}
+
+struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Publishes a message as the authenticated caller.
+    async fn publish(
+        &self,
+        ctx: &Context<'_>,
+        topic: String,
+        #[graphql(default)] qos: u8,
+        #[graphql(default)] retain: bool,
+        payload: String,
+    ) -> Result<bool> {
+        let state = ctx.data_unchecked::<Arc<ServiceState>>();
+        let (addr, authorization) = caller(ctx);
+        let uid = crate::api::authenticate(state, authorization)
+            .await
+            .map_err(response_error)?;
+        let msg = PublishRequest::new(topic, qos, retain, payload)
+            .into_message()
+            .map_err(Error::new)?;
+        crate::api::publish_message(state, addr, Some(&uid), msg)
+            .await
+            .map_err(|(_, reason)| Error::new(reason))?;
+        Ok(true)
+    }
+
+    /// Disconnects a client, returns `false` if the client is not connected.
+    ///
+    /// The caller must be an admin user.
+    async fn kick(&self, ctx: &Context<'_>, client_id: String) -> Result<bool> {
+        let state = ctx.data_unchecked::<Arc<ServiceState>>();
+        let (_, authorization) = caller(ctx);
+        crate::api::authorize_admin(state, &ctx.data_unchecked::<AdminUsers>().0, authorization)
+            .await
+            .map_err(response_error)?;
+        Ok(state.kick(&client_id).await)
+    }
+}
+
+struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    async fn metrics(&self, ctx: &Context<'_>) -> impl Stream<Item = Metrics> {
+        ctx.data_unchecked::<Arc<ServiceState>>().metrics_stream()
+    }
+}
+
+pub fn graphql(
+    state: Arc<ServiceState>,
+    admin_users: Arc<[String]>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(state)
+        .data(AdminUsers(admin_users))
+        .finish();
+
+    warp::path!("graphql").and(
+        async_graphql_warp::graphql_subscription(schema.clone())
+            .map(Reply::into_response)
+            .or(async_graphql_warp::graphql(schema)
+                .and(warp::addr::remote())
+                .and(warp::header::optional::<String>("authorization"))
+                .and_then(
+                    |(schema, request): (AdminSchema, async_graphql::Request),
+                     addr: Option<SocketAddr>,
+                     authorization: Option<String>| async move {
+                        let request = request.data(Caller {
+                            addr,
+                            authorization,
+                        });
+                        Ok::<_, Infallible>(
+                            async_graphql_warp::Response::from(schema.execute(request).await)
+                                .into_response(),
+                        )
+                    },
+                ))
+            .unify(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+    use service::plugin::{Plugin, PluginResult};
+
+    use super::*;
+
+    struct AuthPlugin;
+
+    #[async_trait::async_trait]
+    impl Plugin for AuthPlugin {
+        async fn auth(&self, user: &str, password: &str) -> PluginResult<Option<String>> {
+            Ok(
+                matches!((user, password), ("admin", "secret") | ("sunli", "abcdef"))
+                    .then(|| user.to_string()),
+            )
+        }
+    }
+
+    fn create_state() -> Arc<ServiceState> {
+        ServiceState::new(
+            serde_yaml::from_str("{}").unwrap(),
+            vec![("auth", Arc::new(AuthPlugin) as Arc<dyn Plugin>)],
+        )
+        .unwrap()
+    }
+
+    async fn execute(
+        state: &Arc<ServiceState>,
+        credentials: Option<(&str, &str)>,
+        query: &str,
+    ) -> Value {
+        let filter = graphql(state.clone(), vec!["admin".to_string()].into());
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/graphql")
+            .header("content-type", "application/json")
+            .body(format!("{{\"query\": {:?}}}", query));
+        if let Some((username, password)) = credentials {
+            request = request.header(
+                "authorization",
+                format!(
+                    "Basic {}",
+                    base64::encode(format!("{}:{}", username, password))
+                ),
+            );
+        }
+        let resp = request.reply(&filter).await;
+        serde_yaml::from_slice(resp.body()).unwrap()
+    }
+
+    fn error(value: &Value) -> Option<&str> {
+        value["errors"][0]["message"].as_str()
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let state = create_state();
+        let publish = r#"mutation { publish(topic: "a/b", retain: true, payload: "1") }"#;
+
+        let value = execute(&state, None, publish).await;
+        assert_eq!(error(&value), Some("unauthorized"));
+        let value = execute(&state, Some(("sunli", "wrong")), publish).await;
+        assert_eq!(error(&value), Some("unauthorized"));
+        assert!(state.retained_message("a/b").is_none());
+
+        let value = execute(&state, Some(("sunli", "abcdef")), publish).await;
+        assert_eq!(value["data"]["publish"], Value::Bool(true));
+        assert!(state.retained_message("a/b").is_some());
+
+        let value = execute(
+            &state,
+            Some(("sunli", "abcdef")),
+            r#"mutation { publish(topic: "$SYS/a", payload: "1") }"#,
+        )
+        .await;
+        assert!(error(&value).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_kick() {
+        let state = create_state();
+        let kick = r#"mutation { kick(clientId: "c1") }"#;
+
+        let value = execute(&state, None, kick).await;
+        assert_eq!(error(&value), Some("unauthorized"));
+        let value = execute(&state, Some(("sunli", "abcdef")), kick).await;
+        assert_eq!(error(&value), Some("forbidden"));
+        let value = execute(&state, Some(("admin", "secret")), kick).await;
+        assert_eq!(value["data"]["kick"], Value::Bool(false));
+    }
+}
@@ -25,6 +25,7 @@ pub async fn create_plugins(configs: Vec<Value>) -> Result<Vec<(&'static str, Ar
         rsmqtt_plugin_basic_auth::BasicAuth
     );
     register_plugin!("plugin-oso-acl", registry, rsmqtt_plugin_oso_acl::OsoAcl);
+    register_plugin!("plugin-file-acl", registry, rsmqtt_plugin_file_acl::FileAcl);
 
     for config in configs {
         let plugin_type = match config.get("type") {
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytestring::ByteString;
+use service::{CertIdentity, RemoteAddr};
+
+/// The settings of a listener shared by its connections.
+pub struct Listener {
+    pub name: String,
+    pub tls: bool,
+    mountpoint: Option<ByteString>,
+    max_connections: Option<usize>,
+    connections: Arc<AtomicUsize>,
+}
+
+/// Holds a connection slot of a listener until it is dropped.
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Listener {
+    pub fn new(
+        name: impl Into<String>,
+        tls: bool,
+        mountpoint: Option<String>,
+        max_connections: Option<usize>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            tls,
+            mountpoint: mountpoint.map(Into::into),
+            max_connections,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns `None` if the listener has reached the maximum number of connections.
+    pub fn try_accept(&self) -> Option<ConnectionGuard> {
+        let count = self.connections.fetch_add(1, Ordering::SeqCst);
+        let guard = ConnectionGuard(self.connections.clone());
+        match self.max_connections {
+            Some(max_connections) if count >= max_connections => None,
+            _ => Some(guard),
+        }
+    }
+
+    pub fn remote_addr(
+        &self,
+        protocol: &'static str,
+        addr: String,
+        cert_identity: Option<CertIdentity>,
+    ) -> RemoteAddr {
+        RemoteAddr {
+            listener: self.name.clone().into(),
+            protocol: protocol.into(),
+            addr: Some(addr.into()),
+            mountpoint: self.mountpoint.clone(),
+            cert_identity,
+            peer_cred: None,
+            uid: None,
+        }
+    }
+}
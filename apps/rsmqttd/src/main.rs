@@ -3,13 +3,20 @@
 
 mod api;
 mod config;
+mod graphql;
+mod listener;
+mod prometheus;
+mod proxy_protocol;
+mod reload;
 mod server;
+mod tls;
 mod ws_transport;
 
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use service::ServiceState;
 use structopt::StructOpt;
 use tracing_subscriber::fmt;
@@ -18,6 +25,7 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
 use config::Config;
+use reload::Reloader;
 use rsmqttd::create_plugins;
 
 const DEFAULT_CONFIG_FILENAME: &str = ".rsmqttd";
@@ -49,14 +57,9 @@ async fn run() -> Result<()> {
             .filter(|path| path.exists()),
     };
 
-    let config = if let Some(config_filename) = config_filename {
+    let config = if let Some(config_filename) = &config_filename {
         tracing::info!(filename = %config_filename.display(), "load config file");
-
-        serde_yaml::from_str::<Config>(
-            &std::fs::read_to_string(&config_filename)
-                .with_context(|| format!("load config file '{}'.", config_filename.display()))?,
-        )
-        .with_context(|| format!("parse config file '{}'.", config_filename.display()))?
+        Config::load(config_filename)?
     } else {
         tracing::info!("use the default config");
         Config::default()
@@ -69,13 +72,64 @@ async fn run() -> Result<()> {
         let state = state.clone();
         async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(state.config.metrics_update_interval)).await;
+                tokio::time::sleep(Duration::from_secs(state.config().metrics_update_interval))
+                    .await;
                 state.update_metrics().await;
                 state.update_sys_topics();
             }
         }
     });
-    server::run(state, config.network).await
+    let reloader = Arc::new(Reloader::new(state.clone(), config_filename));
+
+    #[cfg(unix)]
+    tokio::spawn({
+        let reloader = reloader.clone();
+        async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut sighup = match signal(SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(err) => {
+                    tracing::error!(error = %err, "failed to listen for SIGHUP");
+                    return;
+                }
+            };
+            while sighup.recv().await.is_some() {
+                reloader.reload().await.ok();
+            }
+        }
+    });
+
+    server::run(state.clone(), config.network, reloader, shutdown_signal()).await?;
+
+    tracing::info!("shutting down");
+    state
+        .shutdown(Duration::from_secs(state.config().shutdown_drain_period))
+        .await
+}
+
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "failed to listen for SIGTERM");
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 }
 
 #[tokio::main]
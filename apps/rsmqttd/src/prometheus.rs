@@ -0,0 +1,318 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use service::{ListenerMetrics, ServiceState};
+use warp::http::header::CONTENT_TYPE;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(output: &mut String, name: &str, ty: &str, help: &str) {
+    writeln!(output, "# HELP rsmqtt_{} {}", name, help).unwrap();
+    writeln!(output, "# TYPE rsmqtt_{} {}", name, ty).unwrap();
+}
+
+fn write_metric(output: &mut String, name: &str, ty: &str, help: &str, value: f64) {
+    write_header(output, name, ty, help);
+    writeln!(output, "rsmqtt_{} {}", name, value).unwrap();
+}
+
+fn write_listener_metric(
+    output: &mut String,
+    name: &str,
+    ty: &str,
+    help: &str,
+    listeners: &[(String, String, Arc<ListenerMetrics>)],
+    value: impl Fn(&ListenerMetrics) -> &AtomicUsize,
+) {
+    write_header(output, name, ty, help);
+    for (listener, protocol, metrics) in listeners {
+        writeln!(
+            output,
+            "rsmqtt_{}{{listener=\"{}\",protocol=\"{}\"}} {}",
+            name,
+            escape_label_value(listener),
+            escape_label_value(protocol),
+            value(metrics).load(Ordering::SeqCst)
+        )
+        .unwrap();
+    }
+}
+
+fn render(state: &ServiceState) -> String {
+    let metrics = state.metrics();
+    let listeners = state.listener_metrics();
+    let mut output = String::new();
+
+    write_metric(
+        &mut output,
+        "uptime_seconds",
+        "gauge",
+        "Number of seconds since the server started.",
+        metrics.uptime as f64,
+    );
+
+    write_listener_metric(
+        &mut output,
+        "bytes_received_total",
+        "counter",
+        "Total number of bytes received.",
+        &listeners,
+        |m| &m.bytes_received,
+    );
+    write_listener_metric(
+        &mut output,
+        "bytes_sent_total",
+        "counter",
+        "Total number of bytes sent.",
+        &listeners,
+        |m| &m.bytes_sent,
+    );
+    write_listener_metric(
+        &mut output,
+        "messages_received_total",
+        "counter",
+        "Total number of MQTT packets received.",
+        &listeners,
+        |m| &m.msgs_received,
+    );
+    write_listener_metric(
+        &mut output,
+        "messages_sent_total",
+        "counter",
+        "Total number of MQTT packets sent.",
+        &listeners,
+        |m| &m.msgs_sent,
+    );
+    write_listener_metric(
+        &mut output,
+        "publish_messages_received_total",
+        "counter",
+        "Total number of PUBLISH messages received.",
+        &listeners,
+        |m| &m.pub_msgs_received,
+    );
+    write_listener_metric(
+        &mut output,
+        "publish_messages_sent_total",
+        "counter",
+        "Total number of PUBLISH messages sent.",
+        &listeners,
+        |m| &m.pub_msgs_sent,
+    );
+    write_listener_metric(
+        &mut output,
+        "publish_bytes_received_total",
+        "counter",
+        "Total number of PUBLISH payload bytes received.",
+        &listeners,
+        |m| &m.pub_bytes_received,
+    );
+    write_listener_metric(
+        &mut output,
+        "publish_bytes_sent_total",
+        "counter",
+        "Total number of PUBLISH payload bytes sent.",
+        &listeners,
+        |m| &m.pub_bytes_sent,
+    );
+    write_listener_metric(
+        &mut output,
+        "sockets",
+        "gauge",
+        "Number of open sockets.",
+        &listeners,
+        |m| &m.socket_connections,
+    );
+    write_listener_metric(
+        &mut output,
+        "clients_connected",
+        "gauge",
+        "Number of connected clients.",
+        &listeners,
+        |m| &m.connection_count,
+    );
+
+    write_metric(
+        &mut output,
+        "publish_messages_dropped_total",
+        "counter",
+        "Total number of PUBLISH messages dropped.",
+        metrics.publish_messages_dropped as f64,
+    );
+    write_metric(
+        &mut output,
+        "clients_expired_total",
+        "counter",
+        "Total number of expired sessions.",
+        metrics.clients_expired as f64,
+    );
+    write_metric(
+        &mut output,
+        "clients_disconnected",
+        "gauge",
+        "Number of sessions whose client is disconnected.",
+        metrics.clients_disconnected as f64,
+    );
+    write_metric(
+        &mut output,
+        "clients_maximum",
+        "gauge",
+        "Maximum number of clients connected at the same time.",
+        metrics.clients_maximum as f64,
+    );
+    write_metric(
+        &mut output,
+        "clients_total",
+        "gauge",
+        "Number of sessions.",
+        metrics.clients_total as f64,
+    );
+    write_metric(
+        &mut output,
+        "messages_inflight",
+        "gauge",
+        "Number of messages waiting for acknowledgement.",
+        metrics.messages_inflight as f64,
+    );
+    write_metric(
+        &mut output,
+        "retained_messages",
+        "gauge",
+        "Number of retained messages.",
+        metrics.retained_messages_count as f64,
+    );
+    write_metric(
+        &mut output,
+        "store_messages",
+        "gauge",
+        "Number of messages queued in the sessions.",
+        metrics.store_messages_count as f64,
+    );
+    write_metric(
+        &mut output,
+        "store_messages_bytes",
+        "gauge",
+        "Number of payload bytes queued in the sessions.",
+        metrics.store_messages_bytes as f64,
+    );
+    write_metric(
+        &mut output,
+        "subscriptions",
+        "gauge",
+        "Number of subscriptions.",
+        metrics.subscriptions_count as f64,
+    );
+
+    output
+}
+
+/// Exposes the metrics in the Prometheus text format.
+pub fn metrics(
+    state: Arc<ServiceState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::any().map(move || state.clone()))
+        .map(|state: Arc<ServiceState>| {
+            warp::reply::with_header(render(&state), CONTENT_TYPE, CONTENT_TYPE_TEXT)
+                .into_response()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use service::codec::{Codec, Packet};
+    use service::RemoteAddr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let state = ServiceState::new(serde_yaml::from_str("{}").unwrap(), Vec::new()).unwrap();
+        let filter = metrics(state.clone());
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let remote_addr = RemoteAddr {
+            listener: "tcp-\"1\"".into(),
+            protocol: "tcp".into(),
+            addr: None,
+            mountpoint: None,
+            cert_identity: None,
+            peer_cred: None,
+            uid: None,
+        };
+        tokio::spawn(service::client_loop(
+            state.clone(),
+            server_reader,
+            server_writer,
+            remote_addr,
+        ));
+
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let mut codec = Codec::new(client_reader, client_writer);
+        for packet in &[
+            "{type: connect, level: V4, client_id: a}",
+            "{type: publish, level: V4, topic: a/b, qos: AtMostOnce, payload: hello}",
+        ] {
+            codec
+                .encode(&serde_yaml::from_str::<Packet>(packet).unwrap())
+                .await
+                .unwrap();
+        }
+        let connack = tokio::time::timeout(Duration::from_secs(3), codec.decode())
+            .await
+            .expect("receive packet")
+            .unwrap()
+            .expect("unexpected eof")
+            .0;
+        assert!(matches!(connack, Packet::ConnAck(_)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let resp = warp::test::request().path("/metrics").reply(&filter).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()[CONTENT_TYPE], CONTENT_TYPE_TEXT);
+
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        for (name, ty) in &[
+            ("uptime_seconds", "gauge"),
+            ("messages_received_total", "counter"),
+            ("publish_bytes_received_total", "counter"),
+            ("clients_connected", "gauge"),
+            ("subscriptions", "gauge"),
+        ] {
+            let ty_line = format!("# TYPE rsmqtt_{} {}", name, ty);
+            let idx = lines
+                .iter()
+                .position(|line| *line == ty_line)
+                .unwrap_or_else(|| panic!("missing `{}`", ty_line));
+            assert!(lines[idx - 1].starts_with(&format!("# HELP rsmqtt_{} ", name)));
+        }
+
+        let labels = r#"{listener="tcp-\"1\"",protocol="tcp"}"#;
+        for (name, value) in &[
+            ("messages_received_total", "2"),
+            ("messages_sent_total", "1"),
+            ("publish_messages_received_total", "1"),
+            ("publish_bytes_received_total", "5"),
+            ("sockets", "1"),
+            ("clients_connected", "1"),
+        ] {
+            let line = format!("rsmqtt_{}{} {}", name, labels, value);
+            assert!(lines.contains(&line.as_str()), "missing `{}`", line);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! The header of the [PROXY protocol](https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt)
+//! sent by load balancers before the data of the client.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// The maximum time to wait for the header after the connection is accepted.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads a v1 or v2 header, returns the address of the client or `None` if the proxy did not
+/// provide one, e.g. for its health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    // the shortest v1 header is longer than the v2 signature
+    let mut prefix = [0; 12];
+    reader.read_exact(&mut prefix).await?;
+
+    if &prefix == V2_SIGNATURE {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut addresses).await?;
+        parse_v2(header[0], header[1], &addresses)
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            anyhow::ensure!(line.len() < V1_MAX_LENGTH, "proxy protocol header too long");
+            line.push(reader.read_u8().await?);
+        }
+        parse_v1(std::str::from_utf8(&line[..line.len() - 2])?)
+    } else {
+        anyhow::bail!("missing proxy protocol header")
+    }
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => anyhow::bail!("invalid proxy protocol header: {}", line),
+    }
+
+    let mut next = || parts.next().context("truncated proxy protocol header");
+    let src_addr = next()?.parse::<IpAddr>()?;
+    let _dst_addr = next()?.parse::<IpAddr>()?;
+    let src_port = next()?.parse::<u16>()?;
+    Ok(Some(SocketAddr::new(src_addr, src_port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    anyhow::ensure!(
+        version_command >> 4 == 2,
+        "unsupported proxy protocol version"
+    );
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => anyhow::bail!("invalid proxy protocol command"),
+    }
+
+    let (addr, port) = match family >> 4 {
+        // AF_INET
+        1 => {
+            anyhow::ensure!(addresses.len() >= 12, "truncated proxy protocol addresses");
+            let octets: [u8; 4] = addresses[..4].try_into().unwrap();
+            (IpAddr::from(Ipv4Addr::from(octets)), &addresses[8..10])
+        }
+        // AF_INET6
+        2 => {
+            anyhow::ensure!(addresses.len() >= 36, "truncated proxy protocol addresses");
+            let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+            (IpAddr::from(Ipv6Addr::from(octets)), &addresses[32..34])
+        }
+        // AF_UNSPEC and AF_UNIX
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(
+        addr,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> Result<Option<SocketAddr>> {
+        let res = read_header(&mut data).await;
+        assert_eq!(data, b"MQTT");
+        res
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\nMQTT")
+                .await
+                .unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 ::1 ::2 56324 1883\r\nMQTT")
+                .await
+                .unwrap(),
+            Some("[::1]:56324".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\nMQTT").await.unwrap(), None);
+        assert!(read_header(&mut &b"PROXY TCP4 1.1.1.1\r\n"[..])
+            .await
+            .is_err());
+        assert!(
+            read_header(&mut &b"\x10\x0f\x00\x04MQTT\x05\x02\x00\x3c"[..])
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0f]);
+        data.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x07, 0x5b]);
+        // a TLV
+        data.extend_from_slice(&[0x04, 0x00, 0x00]);
+        data.extend_from_slice(b"MQTT");
+        assert_eq!(
+            read(&data).await.unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        data.extend_from_slice(b"MQTT");
+        assert_eq!(read(&data).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rsmqttd::create_plugins;
+use service::ServiceState;
+
+use crate::config::Config;
+
+/// Reloads the service config and the plugins from the config file.
+///
+/// The network config is only read at startup.
+pub struct Reloader {
+    state: Arc<ServiceState>,
+    config_filename: Option<PathBuf>,
+}
+
+impl Reloader {
+    pub fn new(state: Arc<ServiceState>, config_filename: Option<PathBuf>) -> Self {
+        Self {
+            state,
+            config_filename,
+        }
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        let res = self.do_reload().await;
+        match &res {
+            Ok(()) => tracing::info!("config reloaded"),
+            Err(err) => tracing::error!(error = %err, "failed to reload config"),
+        }
+        res
+    }
+
+    async fn do_reload(&self) -> Result<()> {
+        let config_filename = self
+            .config_filename
+            .as_deref()
+            .context("the server was started without a config file")?;
+        let config = Config::load(config_filename)?;
+        let plugins = create_plugins(config.plugins).await?;
+        self.state.reload(config.service, plugins)
+    }
+}
@@ -1,113 +1,374 @@
-use std::io::{BufReader, Cursor};
-use std::net::IpAddr;
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use service::{client_loop, RemoteAddr, ServiceState};
+use service::{client_loop, ServiceState};
 use tokio::net::TcpListener;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_rustls::rustls;
+use tokio_stream::StreamExt;
 use warp::{Filter, Reply};
 
-use crate::config::{HttpConfig, NetworkConfig, TcpConfig};
-
-async fn run_tcp_server(state: Arc<ServiceState>, tcp_config: TcpConfig) -> Result<()> {
-    let port = tcp_config.port();
+use crate::config::{
+    HttpConfig, ListenerConfig, ListenerProtocol, NetworkConfig, TlsConfig, WebSocketConfig,
+};
+use crate::listener::Listener;
+use crate::proxy_protocol;
+use crate::reload::Reloader;
+use crate::tls;
 
+async fn run_tcp_listener(
+    state: Arc<ServiceState>,
+    listener: Arc<Listener>,
+    config: ListenerConfig,
+) -> Result<()> {
+    let port = config.port.context("missing port")?;
     tracing::info!(
-        host = %tcp_config.host,
+        listener = %listener.name,
+        host = %config.host,
         port = port,
         "tcp listening",
     );
 
-    if let Some(tls_config) = &tcp_config.tls {
-        let cert_data = std::fs::read(&tls_config.cert)
-            .with_context(|| format!("failed to read certificates file: {}", tls_config.cert))?;
-        let key_data = std::fs::read(&tls_config.key)
-            .with_context(|| format!("failed to read key file: {}", tls_config.cert))?;
-
-        let cert = rustls::internal::pemfile::certs(&mut BufReader::new(Cursor::new(cert_data)))
-            .map_err(|_| anyhow::anyhow!("failed to load tls certificates"))?;
-        let mut keys =
-            rustls::internal::pemfile::rsa_private_keys(&mut BufReader::new(Cursor::new(key_data)))
-                .map_err(|_| anyhow::anyhow!("failed to load tls key"))?;
-        let mut config = ServerConfig::new(rustls::NoClientAuth::new());
-        config
-            .set_single_cert(cert, keys.pop().unwrap())
-            .context("failed to set tls certificate")?;
-        let config = Arc::new(config);
-
-        let listener = TcpListener::bind((tcp_config.host.as_str(), port)).await?;
-
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let acceptor = TlsAcceptor::from(config.clone());
-            if let Ok(stream) = acceptor.accept(stream).await {
-                let state = state.clone();
-                tokio::spawn(async move {
-                    tracing::debug!(
-                        protocol = "tcp",
-                        remote_addr = %addr,
-                        "incoming connection",
-                    );
+    let acceptor = config
+        .tls
+        .as_ref()
+        .map(tls::create_tls_acceptor)
+        .transpose()?;
+    let client_auth = config.tls.as_ref().and_then(|tls| tls.client_auth.clone());
+    let protocol = if acceptor.is_some() { "tls" } else { "tcp" };
+    let proxy_protocol = config.proxy_protocol;
+    let tcp_listener = TcpListener::bind((config.host.as_str(), port)).await?;
 
-                    let (reader, writer) = tokio::io::split(stream);
-                    client_loop(
-                        state,
-                        reader,
-                        writer,
-                        RemoteAddr {
-                            protocol: "tcp".into(),
-                            addr: Some(addr.to_string().into()),
-                        },
-                    )
-                    .await;
+    loop {
+        let (stream, addr) = tcp_listener.accept().await?;
+        let guard = match listener.try_accept() {
+            Some(guard) => guard,
+            None => {
+                tracing::warn!(
+                    listener = %listener.name,
+                    remote_addr = %addr,
+                    "too many connections",
+                );
+                continue;
+            }
+        };
+        let state = state.clone();
+        let listener = listener.clone();
+        let acceptor = acceptor.clone();
+        let client_auth = client_auth.clone();
 
-                    tracing::debug!(
-                        protocol = "tcp",
-                        remote_addr = %addr,
-                        "connection disconnected",
-                    );
-                });
+        tokio::spawn(async move {
+            let _guard = guard;
+            let mut stream = stream;
+
+            let addr = if proxy_protocol {
+                match tokio::time::timeout(
+                    proxy_protocol::HEADER_TIMEOUT,
+                    proxy_protocol::read_header(&mut stream),
+                )
+                .await
+                {
+                    Ok(Ok(client_addr)) => client_addr.unwrap_or(addr),
+                    Ok(Err(err)) => {
+                        tracing::debug!(
+                            remote_addr = %addr,
+                            error = %err,
+                            "proxy protocol",
+                        );
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!(
+                            remote_addr = %addr,
+                            "proxy protocol header timeout",
+                        );
+                        return;
+                    }
+                }
+            } else {
+                addr
+            };
+
+            tracing::debug!(
+                protocol = protocol,
+                remote_addr = %addr,
+                "incoming connection",
+            );
+
+            match acceptor {
+                Some(acceptor) => {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            tracing::debug!(
+                                remote_addr = %addr,
+                                error = %err,
+                                "tls handshake",
+                            );
+                            return;
+                        }
+                    };
+                    let cert_identity = client_auth.as_ref().and_then(|client_auth| {
+                        tls::peer_identity(stream.get_ref().1.peer_certificates()?, client_auth)
+                    });
+                    let remote_addr =
+                        listener.remote_addr(protocol, addr.to_string(), cert_identity);
+                    let (reader, writer) = tokio::io::split(stream);
+                    client_loop(state, reader, writer, remote_addr).await;
+                }
+                None => {
+                    let remote_addr = listener.remote_addr(protocol, addr.to_string(), None);
+                    let (reader, writer) = tokio::io::split(stream);
+                    client_loop(state, reader, writer, remote_addr).await;
+                }
             }
-        }
+
+            tracing::debug!(
+                protocol = protocol,
+                remote_addr = %addr,
+                "connection disconnected",
+            );
+        });
+    }
+}
+
+async fn run_ws_listener(
+    state: Arc<ServiceState>,
+    listener: Arc<Listener>,
+    config: ListenerConfig,
+) -> Result<()> {
+    let port = config.port.context("missing port")?;
+    tracing::info!(
+        listener = %listener.name,
+        host = %config.host,
+        port = port,
+        "websocket listening",
+    );
+
+    let routes = crate::ws_transport::handler(state, listener, config.websocket.clone()).boxed();
+    let addr = (config.host.parse::<IpAddr>()?, port);
+
+    if let Some(tls_config) = &config.tls {
+        tls::serve_warp_tls(routes, tls_config)?.bind(addr).await;
     } else {
-        let listener = TcpListener::bind((tcp_config.host.as_str(), port)).await?;
+        warp::serve(routes).run(addr).await;
+    }
+
+    Ok(())
+}
+
+async fn run_quic_listener(
+    state: Arc<ServiceState>,
+    listener: Arc<Listener>,
+    config: ListenerConfig,
+) -> Result<()> {
+    let port = config.port.context("missing port")?;
+    let tls_config = config.tls.as_ref().context("missing tls config")?;
+    tracing::info!(
+        listener = %listener.name,
+        host = %config.host,
+        port = port,
+        "quic listening",
+    );
 
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let state = state.clone();
+    let mut crypto = tls::create_server_config(tls_config, &[&rustls::version::TLS13])?;
+    crypto.alpn_protocols = vec![b"mqtt".to_vec()];
+    let (_endpoint, mut incoming) = quinn::Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+        SocketAddr::new(config.host.parse()?, port),
+    )?;
+    let client_auth = tls_config.client_auth.clone();
 
-            tokio::spawn(async move {
-                tracing::debug!(
-                    protocol = "tcp",
+    while let Some(connecting) = incoming.next().await {
+        let addr = connecting.remote_address();
+        let guard = match listener.try_accept() {
+            Some(guard) => guard,
+            None => {
+                tracing::warn!(
+                    listener = %listener.name,
                     remote_addr = %addr,
-                    "incoming connection",
+                    "too many connections",
                 );
+                continue;
+            }
+        };
+        let state = state.clone();
+        let listener = listener.clone();
+        let client_auth = client_auth.clone();
 
-                let (reader, writer) = tokio::io::split(stream);
-                client_loop(
-                    state,
-                    reader,
-                    writer,
-                    RemoteAddr {
-                        protocol: "tcp".into(),
-                        addr: Some(addr.to_string().into()),
-                    },
-                )
-                .await;
+        tokio::spawn(async move {
+            let _guard = guard;
 
-                tracing::debug!(
-                    protocol = "tcp",
-                    remote_addr = %addr,
-                    "connection disconnected",
-                );
+            let quinn::NewConnection {
+                connection,
+                mut bi_streams,
+                ..
+            } = match connecting.await {
+                Ok(new_connection) => new_connection,
+                Err(err) => {
+                    tracing::debug!(
+                        remote_addr = %addr,
+                        error = %err,
+                        "quic handshake",
+                    );
+                    return;
+                }
+            };
+
+            // the mqtt connection uses the first bidirectional stream opened by the client, the
+            // quic connection migrates to the new address of the client without interrupting it
+            let (writer, reader) = match bi_streams.next().await {
+                Some(Ok(stream)) => stream,
+                _ => return,
+            };
+
+            tracing::debug!(
+                protocol = "quic",
+                remote_addr = %addr,
+                "incoming connection",
+            );
+
+            let cert_identity = client_auth.as_ref().and_then(|client_auth| {
+                let certs = connection
+                    .peer_identity()?
+                    .downcast::<Vec<rustls::Certificate>>()
+                    .ok()?;
+                tls::peer_identity(&certs, client_auth)
             });
+            let remote_addr = listener.remote_addr("quic", addr.to_string(), cert_identity);
+            client_loop(state, reader, writer, remote_addr).await;
+
+            tracing::debug!(
+                protocol = "quic",
+                remote_addr = %connection.remote_address(),
+                "connection disconnected",
+            );
+        });
+    }
+
+    Ok(())
+}
+
+/// Binds the socket in a private directory and renames it to `path` after setting the
+/// permissions, so that it is never reachable with the permissions of the umask.
+#[cfg(unix)]
+fn bind_unix_socket(path: &str, permissions: Option<&str>) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    let permissions = permissions
+        .map(|permissions| u32::from_str_radix(permissions, 8))
+        .transpose()
+        .with_context(|| format!("invalid permissions of socket: {}", path))?;
+
+    // replace the socket left by the previous process, but nothing else
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) => anyhow::ensure!(
+            metadata.file_type().is_socket(),
+            "failed to bind socket: {} exists and is not a socket",
+            path
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to bind socket: {}", path)),
+    }
+
+    let tmp_dir = format!("{}.{}.tmp", path, std::process::id());
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&tmp_dir)
+        .with_context(|| format!("failed to create directory: {}", tmp_dir))?;
+    let tmp_path = std::path::Path::new(&tmp_dir).join("socket");
+    let res = (|| {
+        let unix_listener = tokio::net::UnixListener::bind(&tmp_path)
+            .with_context(|| format!("failed to bind socket: {}", path))?;
+        if let Some(permissions) = permissions {
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(permissions))
+                .with_context(|| format!("failed to set the permissions of socket: {}", path))?;
         }
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to bind socket: {}", path))?;
+        Ok(unix_listener)
+    })();
+    let _ = std::fs::remove_dir_all(&tmp_dir);
+    res
+}
+
+#[cfg(unix)]
+async fn run_unix_listener(
+    state: Arc<ServiceState>,
+    listener: Arc<Listener>,
+    config: ListenerConfig,
+) -> Result<()> {
+    use service::PeerCred;
+
+    let path = config.path.context("missing path")?;
+    tracing::info!(
+        listener = %listener.name,
+        path = %path,
+        "unix listening",
+    );
+
+    let unix_listener = bind_unix_socket(&path, config.permissions.as_deref())?;
+
+    loop {
+        let (stream, _) = unix_listener.accept().await?;
+        let peer_cred = stream.peer_cred().ok().map(|cred| PeerCred {
+            uid: cred.uid(),
+            gid: cred.gid(),
+        });
+        let guard = match listener.try_accept() {
+            Some(guard) => guard,
+            None => {
+                tracing::warn!(
+                    listener = %listener.name,
+                    peer_cred = ?peer_cred,
+                    "too many connections",
+                );
+                continue;
+            }
+        };
+        let state = state.clone();
+        let listener = listener.clone();
+        let path = path.clone();
+
+        tokio::spawn(async move {
+            let _guard = guard;
+
+            tracing::debug!(
+                protocol = "unix",
+                peer_cred = ?peer_cred,
+                "incoming connection",
+            );
+
+            let mut remote_addr = listener.remote_addr("unix", path, None);
+            remote_addr.peer_cred = peer_cred;
+            let (reader, writer) = tokio::io::split(stream);
+            client_loop(state, reader, writer, remote_addr).await;
+
+            tracing::debug!(
+                protocol = "unix",
+                peer_cred = ?peer_cred,
+                "connection disconnected",
+            );
+        });
     }
 }
 
-async fn run_http_server(state: Arc<ServiceState>, http_config: HttpConfig) -> Result<()> {
+#[cfg(not(unix))]
+async fn run_unix_listener(
+    _state: Arc<ServiceState>,
+    _listener: Arc<Listener>,
+    _config: ListenerConfig,
+) -> Result<()> {
+    anyhow::bail!("unix sockets are not supported on this platform")
+}
+
+async fn run_http_server(
+    state: Arc<ServiceState>,
+    http_config: HttpConfig,
+    reloader: Arc<Reloader>,
+) -> Result<()> {
     let port = http_config.port();
 
     tracing::info!(
@@ -121,7 +382,11 @@ async fn run_http_server(state: Arc<ServiceState>, http_config: HttpConfig) -> R
     if http_config.websocket {
         tracing::info!("websocket transport enabled");
         routes = routes
-            .or(warp::path!("ws").and(crate::ws_transport::handler(state.clone())))
+            .or(crate::ws_transport::handler(
+                state.clone(),
+                Arc::new(Listener::new("http", http_config.tls.is_some(), None, None)),
+                WebSocketConfig::default(),
+            ))
             .unify()
             .boxed();
     }
@@ -129,17 +394,66 @@ async fn run_http_server(state: Arc<ServiceState>, http_config: HttpConfig) -> R
     if http_config.api {
         tracing::info!("api enabled");
 
+        let admin_users: Arc<[String]> = http_config.admin_users.clone().into();
+
         let api = warp::path!("api" / "v1" / ..)
-            .and(crate::api::metrics(state.clone()))
+            .and(
+                crate::api::metrics(state.clone())
+                    .or(crate::api::publish(state.clone()))
+                    .unify()
+                    .or(crate::api::sessions(state.clone()))
+                    .unify()
+                    .or(crate::api::session(state.clone()))
+                    .unify()
+                    .or(crate::api::session_subscriptions(state.clone()))
+                    .unify()
+                    .or(crate::api::disconnect_session(
+                        state.clone(),
+                        admin_users.clone(),
+                    ))
+                    .unify()
+                    .or(crate::api::remove_session(
+                        state.clone(),
+                        admin_users.clone(),
+                    ))
+                    .unify()
+                    .or(crate::api::subscriptions(state.clone()))
+                    .unify()
+                    .or(crate::api::retained_messages(state.clone()))
+                    .unify()
+                    .or(crate::api::retained_message(state.clone()))
+                    .unify()
+                    .or(crate::api::remove_retained_message(
+                        state.clone(),
+                        admin_users.clone(),
+                    ))
+                    .unify()
+                    .or(crate::api::reload(state.clone(), reloader, admin_users))
+                    .unify(),
+            )
+            .boxed();
+        routes = routes
+            .or(api)
+            .unify()
+            .or(crate::prometheus::metrics(state.clone()))
+            .unify()
+            .boxed();
+    }
+
+    if http_config.graphql_api {
+        tracing::info!("graphql api enabled");
+
+        let graphql = warp::path!("api" / ..)
+            .and(crate::graphql::graphql(
+                state.clone(),
+                http_config.admin_users.clone().into(),
+            ))
             .boxed();
-        routes = routes.or(api).unify().boxed();
+        routes = routes.or(graphql).unify().boxed();
     }
 
     if let Some(tls_config) = &http_config.tls {
-        warp::serve(routes)
-            .tls()
-            .cert_path(&tls_config.cert)
-            .key_path(&tls_config.key)
+        tls::serve_warp_tls(routes, tls_config)?
             .bind((http_config.host.parse::<IpAddr>()?, port))
             .await;
     } else {
@@ -151,16 +465,151 @@ async fn run_http_server(state: Arc<ServiceState>, http_config: HttpConfig) -> R
     Ok(())
 }
 
-pub async fn run(state: Arc<ServiceState>, network_config: NetworkConfig) -> Result<()> {
-    let mut servers = Vec::new();
+fn validate_listeners(listeners: &[ListenerConfig]) -> Result<()> {
+    let mut names = HashSet::new();
 
+    for config in listeners {
+        anyhow::ensure!(
+            names.insert(config.name.as_str()),
+            "duplicate listener name: {}",
+            config.name
+        );
+        match config.protocol {
+            ListenerProtocol::Tcp | ListenerProtocol::Ws => anyhow::ensure!(
+                config.port.is_some(),
+                "missing port of listener '{}'",
+                config.name
+            ),
+            ListenerProtocol::Quic => {
+                anyhow::ensure!(
+                    config.port.is_some(),
+                    "missing port of listener '{}'",
+                    config.name
+                );
+                anyhow::ensure!(
+                    config.tls.is_some(),
+                    "missing tls config of listener '{}'",
+                    config.name
+                );
+            }
+            ListenerProtocol::Unix => {
+                anyhow::ensure!(
+                    config.path.is_some(),
+                    "missing path of listener '{}'",
+                    config.name
+                );
+                anyhow::ensure!(
+                    config.tls.is_none(),
+                    "listener '{}' does not support tls",
+                    config.name
+                );
+            }
+        }
+        if let Some(permissions) = &config.permissions {
+            anyhow::ensure!(
+                matches!(u32::from_str_radix(permissions, 8), Ok(mode) if mode <= 0o777),
+                "invalid permissions of listener '{}': {}",
+                config.name,
+                permissions
+            );
+        }
+        anyhow::ensure!(
+            !config.proxy_protocol || config.protocol == ListenerProtocol::Tcp,
+            "listener '{}' does not support the proxy protocol",
+            config.name
+        );
+        if let (ListenerProtocol::Ws, Some(tls_config)) = (config.protocol, &config.tls) {
+            validate_warp_tls(&format!("listener '{}'", config.name), tls_config)?;
+        }
+        if let Some(mountpoint) = &config.mountpoint {
+            anyhow::ensure!(
+                !mountpoint.starts_with('$') && service::valid_topic(mountpoint),
+                "invalid mountpoint of listener '{}': {}",
+                config.name,
+                mountpoint
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Rejects the TLS options that the warp servers of the WebSocket listeners and the HTTP server
+/// don't support.
+fn validate_warp_tls(server: &str, tls_config: &TlsConfig) -> Result<()> {
+    anyhow::ensure!(
+        tls_config.sni.is_empty(),
+        "{} does not support sni certificates",
+        server
+    );
+    if let Some(client_auth) = &tls_config.client_auth {
+        anyhow::ensure!(
+            client_auth.identity.is_none() && !client_auth.use_as_username,
+            "{} does not support client certificate identities",
+            server
+        );
+    }
+    Ok(())
+}
+
+/// Runs the listeners until `shutdown` completes.
+pub async fn run(
+    state: Arc<ServiceState>,
+    network_config: NetworkConfig,
+    reloader: Arc<Reloader>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let mut listeners = network_config.listeners;
     if let Some(tcp_config) = network_config.tcp {
+        listeners.insert(
+            0,
+            ListenerConfig {
+                name: "tcp".to_string(),
+                protocol: ListenerProtocol::Tcp,
+                port: Some(tcp_config.port()),
+                host: tcp_config.host,
+                path: None,
+                permissions: None,
+                tls: tcp_config.tls,
+                max_connections: None,
+                mountpoint: None,
+                proxy_protocol: false,
+                websocket: WebSocketConfig::default(),
+            },
+        );
+    }
+    validate_listeners(&listeners)?;
+    if let Some(tls_config) = network_config
+        .http
+        .as_ref()
+        .and_then(|http_config| http_config.tls.as_ref())
+    {
+        validate_warp_tls("http server", tls_config)?;
+    }
+
+    let mut servers = Vec::new();
+
+    for config in listeners {
         let state = state.clone();
+        let listener = Arc::new(Listener::new(
+            config.name.clone(),
+            config.tls.is_some(),
+            config.mountpoint.clone(),
+            config.max_connections,
+        ));
         servers.push(tokio::spawn(async move {
-            if let Err(err) = run_tcp_server(state, tcp_config).await {
+            let name = listener.name.clone();
+            let res = match config.protocol {
+                ListenerProtocol::Tcp => run_tcp_listener(state, listener, config).await,
+                ListenerProtocol::Ws => run_ws_listener(state, listener, config).await,
+                ListenerProtocol::Quic => run_quic_listener(state, listener, config).await,
+                ListenerProtocol::Unix => run_unix_listener(state, listener, config).await,
+            };
+            if let Err(err) = res {
                 tracing::error!(
+                    listener = %name,
                     error = %err,
-                    "tcp server",
+                    "listener",
                 );
             }
         }));
@@ -169,17 +618,339 @@ pub async fn run(state: Arc<ServiceState>, network_config: NetworkConfig) -> Res
     if let Some(http_config) = network_config.http {
         let state = state.clone();
         servers.push(tokio::spawn(async move {
-            if let Err(err) = run_http_server(state, http_config).await {
+            if let Err(err) = run_http_server(state, http_config, reloader).await {
                 tracing::error!(
                     error = %err,
-                    "tcp server",
+                    "http server",
                 );
             }
         }));
     }
 
+    tokio::select! {
+        _ = shutdown => {}
+        _ = futures_util::future::join_all(servers.iter_mut()) => {}
+    }
+
+    // the connections have been spawned in their own tasks, so only the listeners are stopped
     for handle in servers {
-        handle.await.ok();
+        handle.abort();
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::time::Duration;
+
+    use service::codec::{Codec, Packet};
+
+    use super::*;
+
+    type QuicCodec = Codec<quinn::RecvStream, quinn::SendStream>;
+
+    fn packet(yaml: &str) -> Packet {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    async fn recv(codec: &mut QuicCodec) -> Packet {
+        tokio::time::timeout(Duration::from_secs(3), codec.decode())
+            .await
+            .expect("receive packet")
+            .unwrap()
+            .expect("unexpected eof")
+            .0
+    }
+
+    /// Starts a QUIC listener on a free port of the loopback interface.
+    fn start_quic_listener(state: Arc<ServiceState>) -> SocketAddr {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = serde_yaml::from_str(&format!(
+            r#"
+            name: quic
+            protocol: quic
+            host: 127.0.0.1
+            port: {}
+            tls:
+              cert: {dir}/tests/tls/p256.pem
+              key: {dir}/tests/tls/p256.key
+            "#,
+            port,
+            dir = env!("CARGO_MANIFEST_DIR"),
+        ))
+        .unwrap();
+        let listener = Arc::new(Listener::new("quic", true, None, None));
+        tokio::spawn(run_quic_listener(state, listener, config));
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn quic_client() -> quinn::Endpoint {
+        let ca = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem")).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut &*ca).unwrap());
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"mqtt".to_vec()];
+
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        endpoint
+    }
+
+    /// Connects with the client id `c1` and a session that expires after 60 seconds, returns
+    /// whether the session was present.
+    async fn connect(
+        endpoint: &quinn::Endpoint,
+        addr: SocketAddr,
+        clean_start: bool,
+    ) -> (quinn::Connection, QuicCodec, bool) {
+        let quinn::NewConnection { connection, .. } =
+            endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+        let (writer, reader) = connection.open_bi().await.unwrap();
+        let mut codec = Codec::new(reader, writer);
+        codec
+            .encode(&packet(&format!(
+                r#"
+                type: connect
+                level: V5
+                client_id: c1
+                clean_start: {}
+                properties:
+                  session_expiry_interval: 60
+                "#,
+                clean_start
+            )))
+            .await
+            .unwrap();
+        let session_present = match recv(&mut codec).await {
+            Packet::ConnAck(connack) => {
+                assert!(connack.reason_code.is_success());
+                connack.session_present
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        };
+        (connection, codec, session_present)
+    }
+
+    /// Publishes a QoS 1 message to `test` that the client is subscribed to.
+    async fn publish_to_self(codec: &mut QuicCodec, packet_id: u16, payload: &str) {
+        codec
+            .encode(&packet(&format!(
+                r#"
+                type: publish
+                packet_id: {}
+                qos: AtLeastOnce
+                topic: test
+                payload: "{}"
+                "#,
+                packet_id, payload
+            )))
+            .await
+            .unwrap();
+
+        // the acknowledgement and the delivery may arrive in any order
+        let mut acked = false;
+        let mut delivered = false;
+        for _ in 0..2 {
+            match recv(codec).await {
+                Packet::PubAck(puback) => {
+                    assert_eq!(puback.packet_id.get(), packet_id);
+                    acked = true;
+                }
+                Packet::Publish(publish) => {
+                    assert_eq!(&*publish.topic, "test");
+                    assert_eq!(&*publish.payload, payload.as_bytes());
+                    codec
+                        .encode(&packet(&format!(
+                            "{{type: puback, packet_id: {}, reason_code: Success}}",
+                            publish.packet_id.unwrap()
+                        )))
+                        .await
+                        .unwrap();
+                    delivered = true;
+                }
+                packet => panic!("unexpected packet: {:?}", packet),
+            }
+        }
+        assert!(acked && delivered);
+    }
+
+    fn create_state() -> Arc<ServiceState> {
+        ServiceState::new(
+            serde_yaml::from_str("max_session_expiry_interval: 60").unwrap(),
+            Vec::new(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_quic_listener() {
+        let addr = start_quic_listener(create_state());
+        let endpoint = quic_client();
+
+        let (_connection, mut codec, session_present) = connect(&endpoint, addr, true).await;
+        assert!(!session_present);
+        codec
+            .encode(&packet(
+                r#"
+                type: subscribe
+                packet_id: 1
+                filters:
+                  - path: test
+                    qos: AtLeastOnce
+                "#,
+            ))
+            .await
+            .unwrap();
+        match recv(&mut codec).await {
+            Packet::SubAck(suback) => assert_eq!(suback.packet_id.get(), 1),
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        publish_to_self(&mut codec, 1, "1").await;
+    }
+
+    #[tokio::test]
+    async fn test_quic_listener_keeps_session() {
+        let addr = start_quic_listener(create_state());
+        let endpoint = quic_client();
+
+        let (connection, mut codec, _) = connect(&endpoint, addr, true).await;
+        codec
+            .encode(&packet(
+                r#"
+                type: subscribe
+                packet_id: 1
+                filters:
+                  - path: test
+                    qos: AtLeastOnce
+                "#,
+            ))
+            .await
+            .unwrap();
+        recv(&mut codec).await;
+
+        // the connection migrates to the new address of the client
+        endpoint
+            .rebind(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+        publish_to_self(&mut codec, 1, "1").await;
+
+        // the session is resumed by a new connection
+        codec
+            .encode(&packet(
+                "{type: disconnect, reason_code: NormalDisconnection}",
+            ))
+            .await
+            .unwrap();
+        let eof = tokio::time::timeout(Duration::from_secs(3), codec.decode()).await;
+        assert!(matches!(eof, Ok(Ok(None)) | Ok(Err(_))));
+        connection.close(0u32.into(), b"");
+        let (_connection, mut codec, session_present) = connect(&endpoint, addr, false).await;
+        assert!(session_present);
+        publish_to_self(&mut codec, 2, "2").await;
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("rsmqttd-{}-{}.sock", name, std::process::id()))
+            .display()
+            .to_string()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_socket() {
+        let path = socket_path("bind");
+        let _ = std::fs::remove_file(&path);
+
+        let listener = bind_unix_socket(&path, Some("600")).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        // the socket of the previous process is replaced
+        drop(listener);
+        let _listener = bind_unix_socket(&path, Some("660")).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_socket_not_socket() {
+        let path = socket_path("file");
+        std::fs::write(&path, b"data").unwrap();
+
+        assert!(bind_unix_socket(&path, None).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validate_sni() {
+        let listener = |protocol: &str| -> ListenerConfig {
+            serde_yaml::from_str(&format!(
+                r#"
+                name: {}
+                protocol: {}
+                port: 1883
+                tls:
+                  cert: server.pem
+                  key: server.key
+                  sni:
+                    - server_name: example.com
+                      cert: example.pem
+                      key: example.key
+                "#,
+                protocol, protocol
+            ))
+            .unwrap()
+        };
+
+        validate_listeners(&[listener("tcp"), listener("quic")]).unwrap();
+        assert!(validate_listeners(&[listener("ws")]).is_err());
+    }
+
+    #[test]
+    fn test_validate_client_auth_identity() {
+        let listener = |protocol: &str, client_auth: &str| -> ListenerConfig {
+            serde_yaml::from_str(&format!(
+                r#"
+                name: {}
+                protocol: {}
+                port: 1883
+                tls:
+                  cert: server.pem
+                  key: server.key
+                  client_auth:
+                    ca: ca.pem
+                    {}
+                "#,
+                protocol, protocol, client_auth
+            ))
+            .unwrap()
+        };
+
+        for client_auth in &["required: true", "identity: san", "use_as_username: true"] {
+            validate_listeners(&[listener("tcp", client_auth), listener("quic", client_auth)])
+                .unwrap();
+        }
+        validate_listeners(&[listener("ws", "required: true")]).unwrap();
+        assert!(validate_listeners(&[listener("ws", "identity: cn")]).is_err());
+        assert!(validate_listeners(&[listener("ws", "use_as_username: true")]).is_err());
+    }
+}
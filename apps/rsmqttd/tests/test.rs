@@ -1,13 +1,28 @@
 use std::path::Path;
+use std::sync::Arc;
 
 use rsmqttd::create_plugins;
+use serde_yaml::Value;
+use service::plugin::{Plugin, PluginFactory};
+use testutil::TestPlugin;
+
+/// Creates the plugins of the suite, the `test` plugins are provided by `testutil`.
+async fn create_suite_plugins(values: Vec<Value>) -> Vec<(&'static str, Arc<dyn Plugin>)> {
+    let mut plugins = Vec::new();
+    for value in values {
+        if value.get("type").and_then(Value::as_str) == Some(TestPlugin.name()) {
+            plugins.push((TestPlugin.name(), TestPlugin.create(value).await.unwrap()));
+        } else {
+            plugins.extend(create_plugins(vec![value]).await.unwrap());
+        }
+    }
+    plugins
+}
 
 fn service_test(path: &Path) -> datatest_stable::Result<()> {
     tokio::runtime::Runtime::new()
         .unwrap()
-        .block_on(testutil::run_yaml_file(path, |values| async move {
-            create_plugins(values).await.unwrap()
-        }));
+        .block_on(testutil::run_yaml_file(path, create_suite_plugins));
     Ok(())
 }
 
@@ -334,14 +334,15 @@ impl ConnAck {
 
         data.put_u8({
             let mut flags = 0;
-            if self.session_present {
+            // The first byte of the MQTT 3.1 CONNACK is reserved.
+            if self.session_present && level != ProtocolLevel::V3 {
                 flags |= 0x1;
             }
             flags
         });
 
         match level {
-            ProtocolLevel::V4 => {
+            ProtocolLevel::V3 | ProtocolLevel::V4 => {
                 data.put_u8(Into::<ConnectReasonCodeV4>::into(self.reason_code).into());
             }
             ProtocolLevel::V5 => {
@@ -363,7 +364,7 @@ impl ConnAck {
         let n_reason_code = data.read_u8()?;
 
         let (reason_code, properties) = match level {
-            ProtocolLevel::V4 => {
+            ProtocolLevel::V3 | ProtocolLevel::V4 => {
                 let reason_code = TryInto::<ConnectReasonCodeV4>::try_into(n_reason_code)
                     .map_err(|_| DecodeError::InvalidConnAckReasonCode(n_reason_code))?
                     .into();
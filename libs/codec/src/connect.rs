@@ -285,7 +285,7 @@ impl Connect {
     fn variable_header_length(&self, level: ProtocolLevel) -> Result<usize, EncodeError> {
         let mut len =
             // protocol
-            2 + 4 +
+            2 + (if level == ProtocolLevel::V3 { 6 } else { 4 }) +
             // level
             1 +
             // flags
@@ -335,13 +335,22 @@ impl Connect {
     pub(crate) fn decode(mut data: Bytes, _level: ProtocolLevel) -> Result<Self, DecodeError> {
         // parse header
         let protocol = data.read_string()?;
-        ensure!(protocol == "MQTT", DecodeError::InvalidProtocol(protocol));
+        ensure!(
+            protocol == "MQTT" || protocol == "MQIsdp",
+            DecodeError::InvalidProtocol(protocol)
+        );
 
         let n_level = data.read_u8()?;
         let level = n_level
             .try_into()
             .map_err(|_| DecodeError::UnsupportedProtocolLevel(n_level))?;
 
+        // MQTT 3.1 uses the protocol name "MQIsdp", later versions use "MQTT".
+        ensure!(
+            (protocol == "MQIsdp") == (level == ProtocolLevel::V3),
+            DecodeError::UnsupportedProtocolLevel(n_level)
+        );
+
         let connect_flags = data.read_u8()?;
 
         if connect_flags & CF_WILL == 0 {
@@ -382,14 +391,13 @@ impl Connect {
         let client_id = data.read_string()?;
 
         let last_will = if connect_flags & CF_WILL > 0 {
-            let will_properties_len = data.read_remaining_length()?;
-            ensure!(
-                data.remaining() >= will_properties_len,
-                DecodeError::MalformedPacket
-            );
-
             let mut properties = WillProperties::default();
             if level == ProtocolLevel::V5 {
+                let will_properties_len = data.read_remaining_length()?;
+                ensure!(
+                    data.remaining() >= will_properties_len,
+                    DecodeError::MalformedPacket
+                );
                 properties = WillProperties::decode(data.split_to(will_properties_len))?;
             }
 
@@ -448,7 +456,10 @@ impl Connect {
         data.write_remaining_length(size)?;
 
         // write variable header
-        data.write_string("MQTT")?;
+        data.write_string(match level {
+            ProtocolLevel::V3 => "MQIsdp",
+            ProtocolLevel::V4 | ProtocolLevel::V5 => "MQTT",
+        })?;
         data.put_u8(level.into());
 
         let mut flag = 0;
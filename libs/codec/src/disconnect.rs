@@ -25,6 +25,7 @@ pub enum DisconnectReasonCode {
     NotAuthorized = 0x87,
     ServerBusy = 0x89,
     ServerShuttingDown = 0x8B,
+    BadAuthenticationMethod = 0x8C,
     KeepAliveTimeout = 0x8D,
     SessionTakenOver = 0x8E,
     TopicFilterInvalid = 0x8F,
@@ -163,7 +164,7 @@ impl Disconnect {
     #[inline]
     fn variable_header_length(&self, level: ProtocolLevel) -> Result<usize, EncodeError> {
         match level {
-            ProtocolLevel::V4 => Ok(0),
+            ProtocolLevel::V3 | ProtocolLevel::V4 => Ok(0),
             ProtocolLevel::V5 => {
                 if !self.properties.is_empty() {
                     let properties_len = self.properties.bytes_length()?;
@@ -188,7 +189,7 @@ impl Disconnect {
 
     pub(crate) fn decode(mut data: Bytes, level: ProtocolLevel) -> Result<Self, DecodeError> {
         match level {
-            ProtocolLevel::V4 => {
+            ProtocolLevel::V3 | ProtocolLevel::V4 => {
                 if !data.is_empty() {
                     return Err(DecodeError::MalformedPacket);
                 }
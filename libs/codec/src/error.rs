@@ -66,6 +66,9 @@ pub enum DecodeError {
     #[error("invalid pub comp property: {0}")]
     InvalidPubCompProperty(u8),
 
+    #[error("invalid auth property: {0}")]
+    InvalidAuthProperty(u8),
+
     #[error("invalid conn ack reason code: {0}")]
     InvalidConnAckReasonCode(u8),
 
@@ -90,6 +93,9 @@ pub enum DecodeError {
     #[error("invalid unsub ack reason code: {0}")]
     InvalidUnsubAckReasonCode(u8),
 
+    #[error("invalid auth reason code: {0}")]
+    InvalidAuthReasonCode(u8),
+
     #[error("invalid packet id: 0")]
     InvalidPacketId,
 
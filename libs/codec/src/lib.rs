@@ -3,6 +3,7 @@
 
 #[macro_use]
 mod macros;
+mod auth;
 mod codec;
 mod connack;
 mod connect;
@@ -24,6 +25,7 @@ mod unsuback;
 mod unsubscribe;
 mod writer;
 
+pub use auth::{Auth, AuthProperties, AuthReasonCode};
 pub use codec::Codec;
 pub use connack::{ConnAck, ConnAckProperties, ConnectReasonCode};
 pub use connect::{Connect, ConnectProperties, LastWill, WillProperties};
@@ -2,8 +2,8 @@ use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    ConnAck, Connect, DecodeError, Disconnect, EncodeError, ProtocolLevel, PubAck, PubComp, PubRec,
-    PubRel, Publish, SubAck, Subscribe, UnsubAck, Unsubscribe,
+    Auth, ConnAck, Connect, DecodeError, Disconnect, EncodeError, ProtocolLevel, PubAck, PubComp,
+    PubRec, PubRel, Publish, SubAck, Subscribe, UnsubAck, Unsubscribe,
 };
 
 pub const RESERVED: u8 = 0;
@@ -21,7 +21,7 @@ pub const UNSUBACK: u8 = 11;
 pub const PINGREQ: u8 = 12;
 pub const PINGRESP: u8 = 13;
 pub const DISCONNECT: u8 = 14;
-// const AUTH: u8 = 15;
+pub const AUTH: u8 = 15;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
@@ -40,6 +40,7 @@ pub enum Packet {
     PingReq,
     PingResp,
     Disconnect(Disconnect),
+    Auth(Auth),
 }
 
 impl Packet {
@@ -60,6 +61,7 @@ impl Packet {
             PINGREQ => Self::PingReq,
             PINGRESP => Self::PingResp,
             DISCONNECT => Self::Disconnect(Disconnect::decode(data, level)?),
+            AUTH => Self::Auth(Auth::decode(data, level)?),
             n => return Err(DecodeError::UnknownPacketType(n)),
         };
         Ok(packet)
@@ -92,6 +94,7 @@ impl Packet {
                 Ok(())
             }
             Packet::Disconnect(disconnect) => disconnect.encode(data, level, max_size),
+            Packet::Auth(auth) => auth.encode(data, level, max_size),
         }
     }
 }
@@ -11,6 +11,14 @@ impl Default for PacketIdAllocator {
 }
 
 impl PacketIdAllocator {
+    /// Creates an allocator that continues after the identifier `last`.
+    #[inline]
+    pub fn after(last: NonZeroU16) -> Self {
+        let mut allocator = Self(last.get());
+        allocator.take();
+        allocator
+    }
+
     #[inline]
     pub fn take(&mut self) -> NonZeroU16 {
         let id = self.0;
@@ -110,7 +110,7 @@ impl PubAck {
     #[inline]
     fn variable_header_length(&self, level: ProtocolLevel) -> Result<usize, EncodeError> {
         match level {
-            ProtocolLevel::V4 => Ok(2),
+            ProtocolLevel::V3 | ProtocolLevel::V4 => Ok(2),
             ProtocolLevel::V5 => {
                 if !self.properties.is_empty() {
                     let properties_len = self.properties.bytes_length()?;
@@ -180,7 +180,9 @@ impl SubAck {
 
         for code in self.reason_codes.iter().copied() {
             match level {
-                ProtocolLevel::V4 => data.put_u8(Into::<SubscribeReasonCodeV4>::into(code).into()),
+                ProtocolLevel::V3 | ProtocolLevel::V4 => {
+                    data.put_u8(Into::<SubscribeReasonCodeV4>::into(code).into())
+                }
                 ProtocolLevel::V5 => data.put_u8(code.into()),
             }
         }
@@ -208,7 +210,7 @@ impl SubAck {
             let n_reason_code = data.read_u8()?;
 
             match level {
-                ProtocolLevel::V4 => {
+                ProtocolLevel::V3 | ProtocolLevel::V4 => {
                     reason_codes.push(
                         TryInto::<SubscribeReasonCodeV4>::try_into(n_reason_code)
                             .map_err(|_| DecodeError::InvalidSubAckReasonCode(n_reason_code))?
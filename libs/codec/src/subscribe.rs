@@ -110,7 +110,7 @@ impl SubscribeFilter {
         let path = data.read_string()?;
 
         match level {
-            ProtocolLevel::V4 => {
+            ProtocolLevel::V3 | ProtocolLevel::V4 => {
                 let options = data.read_u8()?;
                 if options & 0b11111100 > 0 {
                     return Err(DecodeError::MalformedPacket);
@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 )]
 #[repr(u8)]
 pub enum ProtocolLevel {
+    V3 = 3,
     V4 = 4,
     V5 = 5,
 }
@@ -26,6 +27,7 @@ pub struct Login {
     PartialEq,
     Ord,
     PartialOrd,
+    Hash,
     IntoPrimitive,
     TryFromPrimitive,
     Serialize,
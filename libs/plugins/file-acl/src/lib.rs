@@ -0,0 +1,116 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::default_trait_access)]
+
+mod rules;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_yaml::Value;
+use service::plugin::{AclRequest, Plugin, PluginFactory, PluginResult};
+use service::WatchedFile;
+
+use rules::Rules;
+
+/// How often the rule file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    /// The path of the rule file.
+    file: String,
+}
+
+pub struct FileAcl;
+
+#[async_trait::async_trait]
+impl PluginFactory for FileAcl {
+    fn name(&self) -> &'static str {
+        "file-acl"
+    }
+
+    async fn create(&self, config: Value) -> PluginResult<Arc<dyn Plugin>> {
+        let config: Config = serde_yaml::from_value(config)?;
+        let rules = WatchedFile::open(config.file, CHECK_INTERVAL, Rules::parse).await?;
+        Ok(Arc::new(FileAclImpl { rules }))
+    }
+}
+
+struct FileAclImpl {
+    rules: Arc<WatchedFile<Rules>>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for FileAclImpl {
+    async fn check_acl(&self, request: &AclRequest<'_>) -> PluginResult<bool> {
+        Ok(self.rules.get().check(
+            request.client_id,
+            request.uid,
+            request.action,
+            request.topic,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    use service::plugin::Action;
+
+    use super::*;
+
+    struct TempFile(std::path::PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Writes the file with a modified time that differs from the previous one.
+    fn write(path: &std::path::Path, data: &str, modified: SystemTime) {
+        std::fs::write(path, data).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn allowed(plugin: &FileAclImpl, topic: &str) -> bool {
+        plugin
+            .rules
+            .get()
+            .check(Some("c1"), None, Action::Publish, topic)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let file = TempFile(
+            std::env::temp_dir().join(format!("rsmqtt-file-acl-{}.acl", std::process::id())),
+        );
+        let now = SystemTime::now();
+        write(&file.0, "topic write a", now);
+        let plugin = FileAclImpl {
+            rules: WatchedFile::open(file.0.display().to_string(), CHECK_INTERVAL, Rules::parse)
+                .await
+                .unwrap(),
+        };
+        assert!(allowed(&plugin, "a"));
+        assert!(!allowed(&plugin, "b"));
+
+        write(&file.0, "topic write b", now + Duration::from_secs(1));
+        plugin.rules.reload_if_changed().await;
+        assert!(!allowed(&plugin, "a"));
+        assert!(allowed(&plugin, "b"));
+
+        // the previous rules are kept if the file is invalid
+        write(&file.0, "topic read", now + Duration::from_secs(2));
+        plugin.rules.reload_if_changed().await;
+        assert!(allowed(&plugin, "b"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use service::filter_util;
+use service::plugin::Action;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(self, action: Action) -> bool {
+        match action {
+            Action::Publish => matches!(self, Access::Write | Access::ReadWrite),
+            Action::Subscribe | Action::Deliver => {
+                matches!(self, Access::Read | Access::ReadWrite)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    access: Access,
+    topic: String,
+}
+
+impl Rule {
+    /// Parses `[read|write|readwrite] <topic>`, the access is `readwrite` if it is omitted.
+    fn parse(args: &str) -> Result<Self> {
+        let (access, topic) = match args.split_once(char::is_whitespace) {
+            Some(("read", topic)) => (Access::Read, topic.trim()),
+            Some(("write", topic)) => (Access::Write, topic.trim()),
+            Some(("readwrite", topic)) => (Access::ReadWrite, topic.trim()),
+            None if matches!(args, "read" | "write" | "readwrite") => {
+                anyhow::bail!("missing topic")
+            }
+            _ => (Access::ReadWrite, args),
+        };
+        anyhow::ensure!(
+            filter_util::parse_filter(topic).is_some(),
+            "invalid topic: {}",
+            topic
+        );
+        Ok(Self {
+            access,
+            topic: topic.to_string(),
+        })
+    }
+}
+
+/// The rules of an ACL file, an action is denied unless a rule allows it.
+#[derive(Debug, Default)]
+pub struct Rules {
+    /// The `topic` rules before the first `user` line, they apply to the anonymous clients.
+    anonymous: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+    /// The `pattern` rules, they apply to all the clients.
+    patterns: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut rules = Rules::default();
+        let mut user = None;
+
+        for (idx, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, args) = match line.split_once(char::is_whitespace) {
+                Some((keyword, args)) => (keyword, args.trim()),
+                None => (line, ""),
+            };
+            match keyword {
+                "user" => {
+                    anyhow::ensure!(!args.is_empty(), "line {}: missing username", idx + 1);
+                    user = Some(args.to_string());
+                }
+                "topic" => {
+                    let rule = Rule::parse(args).with_context(|| format!("line {}", idx + 1))?;
+                    match &user {
+                        Some(user) => rules.users.entry(user.clone()).or_default().push(rule),
+                        None => rules.anonymous.push(rule),
+                    }
+                }
+                "pattern" => {
+                    let rule = Rule::parse(args).with_context(|| format!("line {}", idx + 1))?;
+                    rules.patterns.push(rule);
+                }
+                _ => anyhow::bail!("line {}: unknown keyword: {}", idx + 1, keyword),
+            }
+        }
+
+        Ok(rules)
+    }
+
+    pub fn check(
+        &self,
+        client_id: Option<&str>,
+        uid: Option<&str>,
+        action: Action,
+        topic: &str,
+    ) -> bool {
+        let rules = match uid {
+            Some(uid) => self.users.get(uid).map(Vec::as_slice).unwrap_or_default(),
+            None => &self.anonymous,
+        };
+        if rules.iter().any(|rule| {
+            rule.access.allows(action) && filter_util::matches_topic(&rule.topic, topic)
+        }) {
+            return true;
+        }
+
+        self.patterns.iter().any(|rule| {
+            rule.access.allows(action)
+                && matches!(
+                    substitute(&rule.topic, client_id, uid),
+                    Some(filter) if filter_util::matches_topic(&filter, topic)
+                )
+        })
+    }
+}
+
+/// Replaces `%c` with the client id and `%u` with the username, returns `None` if the pattern
+/// needs a value that is missing or contains a wildcard or a topic level separator.
+fn substitute<'a>(
+    pattern: &'a str,
+    client_id: Option<&str>,
+    uid: Option<&str>,
+) -> Option<Cow<'a, str>> {
+    let mut topic = Cow::Borrowed(pattern);
+    for (placeholder, value) in [("%c", client_id), ("%u", uid)].iter() {
+        if topic.contains(placeholder) {
+            let value = value.filter(|value| !value.contains(&['+', '#', '/'][..]))?;
+            topic = Cow::Owned(topic.replace(placeholder, value));
+        }
+    }
+    Some(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+# anonymous clients
+topic read public/#
+
+user alice
+topic readwrite alice/#
+topic write sensors/+/temp
+
+pattern read devices/%c/#
+pattern write users/%u/status
+"#;
+
+    #[test]
+    fn test_check() {
+        let rules = Rules::parse(RULES).unwrap();
+
+        assert!(rules.check(Some("c1"), None, Action::Subscribe, "public/#"));
+        assert!(!rules.check(Some("c1"), None, Action::Publish, "public/a"));
+        assert!(!rules.check(Some("c1"), None, Action::Subscribe, "#"));
+
+        assert!(rules.check(Some("c1"), Some("alice"), Action::Publish, "alice/a"));
+        assert!(rules.check(Some("c1"), Some("alice"), Action::Deliver, "alice/a"));
+        assert!(rules.check(Some("c1"), Some("alice"), Action::Publish, "sensors/1/temp"));
+        assert!(!rules.check(
+            Some("c1"),
+            Some("alice"),
+            Action::Subscribe,
+            "sensors/+/temp"
+        ));
+        assert!(!rules.check(Some("c1"), Some("alice"), Action::Subscribe, "public/a"));
+        assert!(!rules.check(Some("c1"), Some("bob"), Action::Publish, "alice/a"));
+
+        assert!(rules.check(Some("c1"), Some("bob"), Action::Subscribe, "devices/c1/+"));
+        assert!(!rules.check(Some("c1"), Some("bob"), Action::Subscribe, "devices/c2/+"));
+        assert!(!rules.check(Some("c/1"), Some("bob"), Action::Deliver, "devices/c/1/a"));
+        assert!(rules.check(Some("c1"), Some("bob"), Action::Publish, "users/bob/status"));
+        assert!(!rules.check(Some("c1"), None, Action::Publish, "users/%u/status"));
+        assert!(!rules.check(None, None, Action::Deliver, "devices/%c/a"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Rules::parse("topic read a/b#").is_err());
+        assert!(Rules::parse("user").is_err());
+        assert!(Rules::parse("deny a").is_err());
+        assert!(Rules::parse("topic read").is_err());
+        assert!(Rules::parse("user alice\ntopic readwrite").is_err());
+        assert!(Rules::parse("pattern write").is_err());
+    }
+}
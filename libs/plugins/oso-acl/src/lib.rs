@@ -4,16 +4,23 @@
 mod types;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use oso::{Oso, PolarClass};
 use serde::Deserialize;
 use serde_yaml::Value;
-use service::plugin::{Action, Plugin, PluginFactory, PluginResult};
-use service::RemoteAddr;
+use service::plugin::{AclRequest, Action, Plugin, PluginFactory, PluginResult};
+use service::WatchedFile;
+
+/// How often the rules file is checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Deserialize)]
 struct Config {
-    rules: String,
+    /// The inline Polar rules.
+    rules: Option<String>,
+    /// The path of a Polar file, it is reloaded when it changes.
+    file: Option<String>,
 }
 
 pub struct OsoAcl;
@@ -26,57 +33,211 @@ impl PluginFactory for OsoAcl {
 
     async fn create(&self, config: Value) -> PluginResult<Arc<dyn Plugin>> {
         let config: Config = serde_yaml::from_value(config)?;
-        let mut oso = Oso::new();
-
-        oso.register_class(
-            types::Connection::get_polar_class_builder()
-                .add_attribute_getter("protocol", |conn| conn.addr.protocol.to_string())
-                .add_attribute_getter("addr", |conn| {
-                    conn.addr
-                        .addr
-                        .as_ref()
-                        .map(|addr| addr.to_string())
-                        .unwrap_or_default()
-                })
-                .add_attribute_getter("uid", |conn| {
-                    conn.uid
-                        .as_ref()
-                        .map(|uid| uid.to_string())
-                        .unwrap_or_default()
-                })
-                .build(),
-        )?;
-
-        oso.load_str(&config.rules)?;
-        Ok(Arc::new(OsoAclImpl { oso }))
+        let policy = match (config.rules, config.file) {
+            (Some(rules), None) => Policy::Inline(Arc::new(create_oso(&rules)?)),
+            (None, Some(file)) => {
+                Policy::File(WatchedFile::open(file, CHECK_INTERVAL, create_oso).await?)
+            }
+            _ => anyhow::bail!("exactly one of `rules` and `file` is required"),
+        };
+        Ok(Arc::new(OsoAclImpl { policy }))
+    }
+}
+
+enum Policy {
+    Inline(Arc<Oso>),
+    File(Arc<WatchedFile<Oso>>),
+}
+
+impl Policy {
+    fn get(&self) -> Arc<Oso> {
+        match self {
+            Policy::Inline(oso) => oso.clone(),
+            Policy::File(file) => file.get(),
+        }
     }
 }
 
 struct OsoAclImpl {
-    oso: Oso,
+    policy: Policy,
 }
 
 #[async_trait::async_trait]
 impl Plugin for OsoAclImpl {
-    async fn check_acl(
-        &self,
-        remote_addr: &RemoteAddr,
-        uid: Option<&str>,
-        action: Action,
-        topic: &str,
-    ) -> PluginResult<bool> {
+    async fn check_acl(&self, request: &AclRequest<'_>) -> PluginResult<bool> {
         let connection_info = types::Connection {
-            addr: remote_addr.clone(),
-            uid: uid.map(ToString::to_string),
+            addr: request.remote_addr.clone(),
+            client_id: request.client_id.map(ToString::to_string),
+            uid: request.uid.map(ToString::to_string),
+            level: request.level.map(Into::into),
+            qos: request.qos.into(),
+            retain: request.retain,
         };
 
-        Ok(self.oso.is_allowed(
+        let oso = self.policy.get();
+        Ok(oso.is_allowed(
             connection_info,
-            match action {
+            match request.action {
                 Action::Publish => "pub",
                 Action::Subscribe => "sub",
+                Action::Deliver => "deliver",
             },
-            topic,
+            request.topic,
         )?)
     }
 }
+
+fn new_oso() -> anyhow::Result<Oso> {
+    let mut oso = Oso::new();
+
+    oso.register_class(
+        types::Connection::get_polar_class_builder()
+            .add_attribute_getter("listener", |conn| conn.addr.listener.to_string())
+            .add_attribute_getter("protocol", |conn| conn.addr.protocol.to_string())
+            .add_attribute_getter("addr", |conn| {
+                conn.addr
+                    .addr
+                    .as_ref()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default()
+            })
+            .add_attribute_getter("cert_identity", |conn| {
+                conn.addr
+                    .cert_identity
+                    .as_ref()
+                    .map(|identity| identity.name.to_string())
+                    .unwrap_or_default()
+            })
+            .add_attribute_getter("peer_uid", |conn| {
+                conn.addr
+                    .peer_cred
+                    .map(|cred| i64::from(cred.uid))
+                    .unwrap_or(-1)
+            })
+            .add_attribute_getter("peer_gid", |conn| {
+                conn.addr
+                    .peer_cred
+                    .map(|cred| i64::from(cred.gid))
+                    .unwrap_or(-1)
+            })
+            .add_attribute_getter("client_id", |conn| {
+                conn.client_id.clone().unwrap_or_default()
+            })
+            .add_attribute_getter("uid", |conn| conn.uid.clone().unwrap_or_default())
+            .add_attribute_getter("level", |conn| conn.level.unwrap_or_default())
+            .add_attribute_getter("qos", |conn| conn.qos)
+            .add_attribute_getter("retain", |conn| conn.retain)
+            .build(),
+    )?;
+
+    Ok(oso)
+}
+
+fn create_oso(rules: &str) -> anyhow::Result<Oso> {
+    let oso = new_oso()?;
+    oso.load_str(rules)?;
+    Ok(oso)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    use service::codec::Qos;
+    use service::RemoteAddr;
+
+    use super::*;
+
+    struct TempFile(std::path::PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Writes the file with a modified time that differs from the previous one.
+    fn write(path: &std::path::Path, data: &str, modified: SystemTime) {
+        std::fs::write(path, data).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    async fn allowed(plugin: &OsoAclImpl, topic: &str) -> bool {
+        let remote_addr = RemoteAddr {
+            listener: "tcp".into(),
+            protocol: "tcp".into(),
+            addr: None,
+            mountpoint: None,
+            cert_identity: None,
+            peer_cred: None,
+            uid: None,
+        };
+        plugin
+            .check_acl(&AclRequest {
+                remote_addr: &remote_addr,
+                client_id: Some("c1"),
+                uid: None,
+                level: None,
+                action: Action::Publish,
+                topic,
+                qos: Qos::AtMostOnce,
+                retain: false,
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let file = TempFile(
+            std::env::temp_dir().join(format!("rsmqtt-oso-acl-{}.polar", std::process::id())),
+        );
+        let now = SystemTime::now();
+        write(&file.0, r#"allow(_conn, "pub", "a");"#, now);
+        let plugin = OsoAclImpl {
+            policy: Policy::File(
+                WatchedFile::open(file.0.display().to_string(), CHECK_INTERVAL, create_oso)
+                    .await
+                    .unwrap(),
+            ),
+        };
+        assert!(allowed(&plugin, "a").await);
+        assert!(!allowed(&plugin, "b").await);
+
+        write(
+            &file.0,
+            r#"allow(_conn, "pub", "b");"#,
+            now + Duration::from_secs(1),
+        );
+        reload(&plugin).await;
+        assert!(!allowed(&plugin, "a").await);
+        assert!(allowed(&plugin, "b").await);
+
+        // the previous rules are kept if the file is invalid
+        write(&file.0, "allow(", now + Duration::from_secs(2));
+        reload(&plugin).await;
+        assert!(allowed(&plugin, "b").await);
+    }
+
+    async fn reload(plugin: &OsoAclImpl) {
+        match &plugin.policy {
+            Policy::File(file) => file.reload_if_changed().await,
+            Policy::Inline(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        let create = |config: &str| OsoAcl.create(serde_yaml::from_str(config).unwrap());
+        assert!(create(r#"rules: allow(_conn, "pub", "a");"#).await.is_ok());
+        assert!(create("rules: a.polar").await.is_err());
+        assert!(create("file: missing.polar").await.is_err());
+        assert!(create("{}").await.is_err());
+    }
+}
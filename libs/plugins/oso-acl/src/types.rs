@@ -4,5 +4,10 @@ use service::RemoteAddr;
 #[derive(Clone, PolarClass)]
 pub struct Connection {
     pub addr: RemoteAddr,
+    pub client_id: Option<String>,
     pub uid: Option<String>,
+    /// The protocol level of the client, `None` for the messages published by the HTTP API.
+    pub level: Option<u8>,
+    pub qos: u8,
+    pub retain: bool,
 }
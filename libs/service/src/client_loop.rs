@@ -1,41 +1,80 @@
 use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::fmt::{self, Display, Formatter};
 use std::num::NonZeroU16;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use bytes::Bytes;
 use bytestring::ByteString;
 use codec::{
-    Codec, ConnAck, ConnAckProperties, Connect, ConnectReasonCode, DecodeError, Disconnect,
-    DisconnectProperties, DisconnectReasonCode, EncodeError, LastWill, Packet, PacketIdAllocator,
-    ProtocolLevel, PubAck, PubAckProperties, PubAckReasonCode, PubComp, PubCompProperties,
-    PubCompReasonCode, PubRec, PubRecProperties, PubRecReasonCode, PubRel, PubRelProperties,
-    PubRelReasonCode, Publish, Qos, SubAck, SubAckProperties, Subscribe, SubscribeReasonCode,
-    UnsubAck, UnsubAckProperties, UnsubAckReasonCode, Unsubscribe,
+    Auth, AuthProperties, AuthReasonCode, Codec, ConnAck, ConnAckProperties, Connect,
+    ConnectReasonCode, DecodeError, Disconnect, DisconnectProperties, DisconnectReasonCode,
+    EncodeError, LastWill, Packet, PacketIdAllocator, ProtocolLevel, PubAck, PubAckProperties,
+    PubAckReasonCode, PubComp, PubCompProperties, PubCompReasonCode, PubRec, PubRecProperties,
+    PubRecReasonCode, PubRel, PubRelProperties, PubRelReasonCode, Publish, Qos, SubAck,
+    SubAckProperties, Subscribe, SubscribeReasonCode, UnsubAck, UnsubAckProperties,
+    UnsubAckReasonCode, Unsubscribe,
 };
 use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::{mpsc, Notify};
 
+use crate::config::ServiceConfig;
 use crate::error::Error;
 use crate::filter_util;
 use crate::message::Message;
-use crate::plugin::Action;
-use crate::state::Control;
+use crate::plugin::{AclRequest, Action, EnhancedAuth, EnhancedAuthResult};
+use crate::state::{ConnectionMetrics, Control, PublishOutcome, Publisher};
+use crate::storage::Qos2State;
 use crate::ServiceState;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum Qos2State {
-    Published,
-    Recorded,
+struct EnhancedAuthExchange {
+    authenticator: Box<dyn EnhancedAuth>,
+    /// The CONNECT packet waiting for the exchange to complete, `None` when re-authenticating.
+    connect: Option<(Connect, ConnAckProperties)>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteAddr {
+    /// The name of the listener that accepted the connection.
+    #[serde(default)]
+    pub listener: Cow<'static, str>,
     pub protocol: Cow<'static, str>,
     pub addr: Option<ByteString>,
+    /// The prefix of the listener that is added to the topics of the connection, the clients
+    /// only see the topics under it.
+    #[serde(default)]
+    pub mountpoint: Option<ByteString>,
+    /// The identity of the client certificate if the listener requested one.
+    #[serde(default)]
+    pub cert_identity: Option<CertIdentity>,
+    /// The credentials of the peer process of a Unix socket connection.
+    #[serde(default)]
+    pub peer_cred: Option<PeerCred>,
+    /// The user authenticated by the transport before the CONNECT packet, e.g. with the
+    /// credentials of a WebSocket upgrade request, the login of the CONNECT packet is ignored.
+    #[serde(default)]
+    pub uid: Option<ByteString>,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// The identity extracted from a verified client certificate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertIdentity {
+    /// The subject common name or the first subject alternative name of the certificate.
+    pub name: ByteString,
+    /// Authenticates the client as `name` without a password, the login of the CONNECT packet
+    /// is ignored.
+    #[serde(default)]
+    pub use_as_username: bool,
 }
 
 impl Display for RemoteAddr {
@@ -49,12 +88,29 @@ impl Display for RemoteAddr {
     }
 }
 
+/// The results of the deliver ACL checks of a connection, keyed by the topic, the QoS and the
+/// retain flag of the messages.
+#[derive(Default)]
+struct DeliverAclCache {
+    /// The uid and the settings generation that the results were checked with.
+    key: Option<(Option<ByteString>, usize)>,
+    results: FnvHashMap<(ByteString, Qos, bool), (bool, Instant)>,
+}
+
+/// The maximum number of results in a [`DeliverAclCache`], it is cleared when it is full.
+const DELIVER_ACL_CACHE_SIZE: usize = 1024;
+
 pub struct Connection<R, W> {
     state: Arc<ServiceState>,
+    /// The config when the client connected, it is not affected by [`ServiceState::reload`].
+    config: Arc<ServiceConfig>,
+    metrics: ConnectionMetrics,
     remote_addr: RemoteAddr,
     client_id: Option<ByteString>,
     control_sender: mpsc::UnboundedSender<Control>,
     uid: Option<ByteString>,
+    auth_method: Option<ByteString>,
+    enhanced_auth: Option<EnhancedAuthExchange>,
     notify: Arc<Notify>,
     codec: Codec<R, W>,
     session_expiry_interval: u32,
@@ -64,12 +120,11 @@ pub struct Connection<R, W> {
     receive_out_quota: usize,
     max_topic_alias: usize,
     topic_alias: FnvHashMap<NonZeroU16, ByteString>,
+    deliver_acl_cache: DeliverAclCache,
     keep_alive: u16,
     last_active: Instant,
     last_will: Option<LastWill>,
     packet_id_allocator: PacketIdAllocator,
-    inflight_qos2_messages: FnvHashMap<NonZeroU16, Qos2State>,
-    uncompleted_messages: FnvHashMap<NonZeroU16, Message>,
 }
 
 impl<R, W> Connection<R, W>
@@ -78,6 +133,13 @@ where
     W: AsyncWrite + Send + Unpin,
 {
     async fn send_packet(&mut self, packet: &Packet) -> Result<(), Error> {
+        // Before MQTT 5 the server has no DISCONNECT packet, it just closes the connection.
+        if matches!(packet, Packet::Disconnect(_))
+            && self.codec.protocol_level() != ProtocolLevel::V5
+        {
+            return Ok(());
+        }
+
         tracing::debug!(
             remote_addr = %self.remote_addr,
             packet = ?packet,
@@ -85,12 +147,10 @@ where
         );
         match self.codec.encode(packet).await {
             Ok(packet_size) => {
-                self.state.service_metrics.inc_msgs_sent(1);
-                self.state.service_metrics.inc_bytes_sent(packet_size);
+                self.metrics.inc_msgs_sent(1);
+                self.metrics.inc_bytes_sent(packet_size);
                 if let Packet::Publish(publish) = packet {
-                    self.state
-                        .service_metrics
-                        .inc_pub_bytes_sent(publish.payload.len());
+                    self.metrics.inc_pub_bytes_sent(publish.payload.len());
                 }
                 Ok(())
             }
@@ -113,39 +173,56 @@ where
         .await
     }
 
-    async fn check_acl(&self, action: Action, topic: &str) -> Result<(), Error> {
-        let mut allow = true;
+    async fn acl_allowed(
+        &self,
+        action: Action,
+        topic: &str,
+        qos: Qos,
+        retain: bool,
+    ) -> Result<bool, Error> {
+        self.state
+            .check_acl(&AclRequest {
+                remote_addr: &self.remote_addr,
+                client_id: self.client_id.as_deref(),
+                uid: self.uid.as_deref(),
+                level: Some(self.codec.protocol_level()),
+                action,
+                topic,
+                qos,
+                retain,
+            })
+            .await
+            .map_err(|_| Error::server_disconnect(DisconnectReasonCode::UnspecifiedError))
+    }
 
-        for (name, plugin) in &self.state.plugins {
-            match plugin
-                .check_acl(&self.remote_addr, self.uid.as_deref(), action, topic)
-                .await
-            {
-                Ok(false) => {
-                    allow = false;
-                    break;
-                }
-                Ok(true) => {}
-                Err(err) => {
-                    tracing::error!(
-                        plugin = %name,
-                        error = %err,
-                        "failed to call plugin::check_acl",
-                    );
-                    return Err(Error::server_disconnect(
-                        DisconnectReasonCode::UnspecifiedError,
-                    ));
-                }
-            }
+    /// Returns `true` if the client is allowed to receive the message.
+    async fn check_deliver_acl(&mut self, msg: &Message) -> Result<bool, Error> {
+        if !self.config.deliver_acl {
+            return Ok(true);
         }
 
-        if !allow {
-            return Err(Error::server_disconnect(
-                DisconnectReasonCode::NotAuthorized,
-            ));
+        // the results are invalid when the client re-authenticates or the plugins are reloaded
+        let key = Some((self.uid.clone(), self.state.generation()));
+        let cache = &mut self.deliver_acl_cache;
+        if cache.key != key || cache.results.len() >= DELIVER_ACL_CACHE_SIZE {
+            cache.key = key;
+            cache.results.clear();
+        }
+        let ttl = Duration::from_secs(self.config.deliver_acl_cache_ttl);
+        let cache_key = (msg.topic().clone(), msg.qos(), msg.is_retain());
+        if let Some((allow, checked_at)) = cache.results.get(&cache_key) {
+            if checked_at.elapsed() < ttl {
+                return Ok(*allow);
+            }
         }
 
-        Ok(())
+        let allow = self
+            .acl_allowed(Action::Deliver, msg.topic(), msg.qos(), msg.is_retain())
+            .await?;
+        self.deliver_acl_cache
+            .results
+            .insert(cache_key, (allow, Instant::now()));
+        Ok(allow)
     }
 
     async fn handle_packet(&mut self, packet: Packet) -> Result<(), Error> {
@@ -160,6 +237,7 @@ where
             Packet::Unsubscribe(unsubscribe) => self.handle_unsubscribe(unsubscribe).await,
             Packet::PingReq => self.handle_ping_req().await,
             Packet::Disconnect(disconnect) => self.handle_disconnect(disconnect).await,
+            Packet::Auth(auth) => self.handle_auth(auth).await,
             Packet::SubAck(_) | Packet::ConnAck(_) | Packet::UnsubAck(_) | Packet::PingResp => Err(
                 Error::server_disconnect(DisconnectReasonCode::ProtocolError),
             ),
@@ -169,79 +247,16 @@ where
     async fn handle_connect(&mut self, mut connect: Connect) -> Result<(), Error> {
         let mut conn_ack_properties = ConnAckProperties::default();
 
-        if self.client_id.is_some() {
+        if self.client_id.is_some() || self.enhanced_auth.is_some() {
             return Err(Error::server_disconnect(
                 DisconnectReasonCode::ProtocolError,
             ));
         }
 
-        let mut session_expiry_interval = {
-            match connect.properties.session_expiry_interval {
-                Some(session_expiry_interval)
-                    if session_expiry_interval > self.state.config.max_session_expiry_interval =>
-                {
-                    conn_ack_properties.session_expiry_interval =
-                        Some(self.state.config.max_session_expiry_interval);
-                    self.state.config.max_session_expiry_interval
-                }
-                Some(session_expiry_interval) => session_expiry_interval,
-                None => {
-                    // If the Session Expiry Interval is absent the value 0 is used.
-                    0
-                }
-            }
-        };
-
-        let keep_alive = {
-            if connect.keep_alive > self.state.config.max_keep_alive {
-                conn_ack_properties.server_keep_alive = Some(self.state.config.max_keep_alive);
-                self.state.config.max_keep_alive
-            } else {
-                connect.keep_alive
-            }
-        };
-
-        let receive_in_max = self.state.config.receive_max as usize;
-        let receive_out_max = connect
-            .properties
-            .receive_max
-            .map(|x| x as usize)
-            .unwrap_or(usize::MAX);
-
-        if self.state.config.maximum_qos != Qos::ExactlyOnce {
-            conn_ack_properties.maximum_qos = Some(self.state.config.maximum_qos);
-        }
-
-        let max_packet_size_out = connect.properties.max_packet_size.unwrap_or(u32::MAX);
-        let max_packet_size_in = self.state.config.max_packet_size;
-        if max_packet_size_in != u32::MAX {
-            conn_ack_properties.max_packet_size = Some(max_packet_size_in);
-        }
-
-        if !self.state.config.retain_available {
-            conn_ack_properties.retain_available = Some(false);
-        }
-
-        if !self.state.config.wildcard_subscription_available {
-            conn_ack_properties.wildcard_subscription_available = Some(false);
-        }
-
-        let max_topic_alias = {
-            match connect.properties.topic_alias_max {
-                Some(topic_alias_max) if topic_alias_max > self.state.config.max_topic_alias => {
-                    conn_ack_properties.topic_alias_max = Some(self.state.config.max_topic_alias);
-                    self.state.config.max_topic_alias
-                }
-                Some(topic_alias_max) => topic_alias_max,
-                None => {
-                    conn_ack_properties.topic_alias_max = Some(self.state.config.max_topic_alias);
-                    self.state.config.max_topic_alias
-                }
-            }
-        };
+        self.config = self.state.config();
 
         if let Some(last_will) = &connect.last_will {
-            if last_will.qos > self.state.config.maximum_qos {
+            if last_will.qos > self.config.maximum_qos {
                 self.send_packet(&Packet::ConnAck(ConnAck {
                     session_present: false,
                     reason_code: ConnectReasonCode::QoSNotSupported,
@@ -251,7 +266,7 @@ where
                 return Ok(());
             }
 
-            if last_will.retain && !self.state.config.retain_available {
+            if last_will.retain && !self.config.retain_available {
                 self.send_packet(&Packet::ConnAck(ConnAck {
                     session_present: false,
                     reason_code: ConnectReasonCode::RetainNotSupported,
@@ -277,6 +292,20 @@ where
             }
         }
 
+        // MQTT 3.1 requires a Client Identifier between 1 and 23 characters and has no way to
+        // assign one on behalf of the client.
+        if connect.level == ProtocolLevel::V3
+            && (connect.client_id.is_empty() || connect.client_id.chars().count() > 23)
+        {
+            self.send_packet(&Packet::ConnAck(ConnAck {
+                session_present: false,
+                reason_code: ConnectReasonCode::ClientIdentifierNotValid,
+                properties: ConnAckProperties::default(),
+            }))
+            .await?;
+            return Err(Error::ServerDisconnect(None));
+        }
+
         if connect.client_id.is_empty() {
             // If the Server rejects the ClientID it MAY respond to the CONNECT packet with a CONNACK
             // using Reason Code 0x85 (Client Identifier not valid) as described in section 4.13 Handling
@@ -295,26 +324,50 @@ where
             conn_ack_properties.assigned_client_identifier = Some(connect.client_id.clone());
         }
 
+        // enhanced auth
+        if let Some(method) = connect.properties.authentication_method.clone() {
+            let authenticator = match self
+                .create_enhanced_auth(&connect.client_id, &method)
+                .await?
+            {
+                Some(authenticator) => authenticator,
+                None => {
+                    self.send_packet(&Packet::ConnAck(ConnAck {
+                        session_present: false,
+                        reason_code: ConnectReasonCode::BadAuthenticationMethod,
+                        properties: ConnAckProperties::default(),
+                    }))
+                    .await?;
+                    return Err(Error::ServerDisconnect(None));
+                }
+            };
+            let data = connect.properties.authentication_data.clone();
+            self.auth_method = Some(method);
+            self.enhanced_auth = Some(EnhancedAuthExchange {
+                authenticator,
+                connect: Some((connect, conn_ack_properties)),
+            });
+            return self.enhanced_auth_step(data).await;
+        }
+
         // auth
         let mut uid = None;
-        if let Some(login) = &connect.login {
-            for (name, plugin) in &self.state.plugins {
-                match plugin.auth(&login.username, &login.password).await {
-                    Ok(Some(res_uid)) => {
-                        uid = Some(res_uid.into());
-                        break;
-                    }
-                    Ok(None) => {}
-                    Err(err) => {
-                        tracing::error!(
-                            plugin = %name,
-                            error = %err,
-                            "failed to call plugin::auth",
-                        );
-                        return Err(Error::internal_error(err));
-                    }
-                }
-            }
+        if let Some(transport_uid) = &self.remote_addr.uid {
+            uid = Some(transport_uid.clone());
+        } else if let Some(identity) = self
+            .remote_addr
+            .cert_identity
+            .as_ref()
+            .filter(|identity| identity.use_as_username)
+        {
+            uid = Some(identity.name.clone());
+        } else if let Some(login) = &connect.login {
+            uid = self
+                .state
+                .auth(&login.username, &login.password)
+                .await
+                .map_err(Error::internal_error)?
+                .map(Into::into);
 
             if uid.is_none() {
                 return Err(Error::server_disconnect(
@@ -323,10 +376,84 @@ where
             }
         }
 
-        if connect.level == ProtocolLevel::V4 && !connect.clean_start {
+        self.finish_connect(connect, conn_ack_properties, uid).await
+    }
+
+    async fn finish_connect(
+        &mut self,
+        mut connect: Connect,
+        mut conn_ack_properties: ConnAckProperties,
+        uid: Option<ByteString>,
+    ) -> Result<(), Error> {
+        let mut session_expiry_interval = {
+            match connect.properties.session_expiry_interval {
+                Some(session_expiry_interval)
+                    if session_expiry_interval > self.config.max_session_expiry_interval =>
+                {
+                    conn_ack_properties.session_expiry_interval =
+                        Some(self.config.max_session_expiry_interval);
+                    self.config.max_session_expiry_interval
+                }
+                Some(session_expiry_interval) => session_expiry_interval,
+                None => {
+                    // If the Session Expiry Interval is absent the value 0 is used.
+                    0
+                }
+            }
+        };
+
+        let keep_alive = {
+            if connect.keep_alive > self.config.max_keep_alive {
+                conn_ack_properties.server_keep_alive = Some(self.config.max_keep_alive);
+                self.config.max_keep_alive
+            } else {
+                connect.keep_alive
+            }
+        };
+
+        let receive_in_max = self.config.receive_max as usize;
+        let receive_out_max = connect
+            .properties
+            .receive_max
+            .map(|x| x as usize)
+            .unwrap_or(usize::MAX);
+
+        if self.config.maximum_qos != Qos::ExactlyOnce {
+            conn_ack_properties.maximum_qos = Some(self.config.maximum_qos);
+        }
+
+        let max_packet_size_out = connect.properties.max_packet_size.unwrap_or(u32::MAX);
+        let max_packet_size_in = self.config.max_packet_size;
+        if max_packet_size_in != u32::MAX {
+            conn_ack_properties.max_packet_size = Some(max_packet_size_in);
+        }
+
+        if !self.config.retain_available {
+            conn_ack_properties.retain_available = Some(false);
+        }
+
+        if !self.config.wildcard_subscription_available {
+            conn_ack_properties.wildcard_subscription_available = Some(false);
+        }
+
+        let max_topic_alias = {
+            match connect.properties.topic_alias_max {
+                Some(topic_alias_max) if topic_alias_max > self.config.max_topic_alias => {
+                    conn_ack_properties.topic_alias_max = Some(self.config.max_topic_alias);
+                    self.config.max_topic_alias
+                }
+                Some(topic_alias_max) => topic_alias_max,
+                None => {
+                    conn_ack_properties.topic_alias_max = Some(self.config.max_topic_alias);
+                    self.config.max_topic_alias
+                }
+            }
+        };
+
+        if matches!(connect.level, ProtocolLevel::V3 | ProtocolLevel::V4) && !connect.clean_start {
             connect.properties.session_expiry_interval =
-                Some(self.state.config.max_session_expiry_interval);
-            session_expiry_interval = self.state.config.max_session_expiry_interval;
+                Some(self.config.max_session_expiry_interval);
+            session_expiry_interval = self.config.max_session_expiry_interval;
         }
 
         {
@@ -337,10 +464,18 @@ where
             connections.insert(connect.client_id.to_string(), self.control_sender.clone());
         }
 
+        if let (Some(mountpoint), Some(last_will)) =
+            (&self.remote_addr.mountpoint, &mut connect.last_will)
+        {
+            last_will.topic = format!("{}{}", mountpoint, last_will.topic).into();
+        }
+
         // create session
         let (session_present, notify) = self.state.storage.create_session(
             &connect.client_id,
             connect.clean_start,
+            session_expiry_interval,
+            self.state.queue_limits(&connect.client_id),
             connect.last_will.clone(),
         );
 
@@ -365,9 +500,9 @@ where
             properties: conn_ack_properties,
         }))
         .await?;
-        self.state.service_metrics.inc_connection_count(1);
+        self.metrics.inc_connection_count(1);
 
-        for (_, plugin) in &self.state.plugins {
+        for (_, plugin) in self.state.plugins().iter() {
             plugin
                 .on_client_connected(
                     &self.remote_addr,
@@ -385,18 +520,48 @@ where
                 .state
                 .storage
                 .get_all_inflight_pub_packets(&connect.client_id);
+
+            // The inflight packets are in the order they were sent, continue after the newest so
+            // the identifiers still awaiting acknowledgement are not reused.
+            if let Some(packet_id) = packets.last().and_then(|publish| publish.packet_id) {
+                self.packet_id_allocator = PacketIdAllocator::after(packet_id);
+            }
+
             for mut publish in packets {
-                publish.dup = true;
                 self.receive_out_quota -= 1;
+
+                // If PUBREC has already been received, continue the handshake with PUBREL
+                // instead of sending the message again.
+                let packet_id = publish.packet_id.unwrap();
+                if self
+                    .state
+                    .storage
+                    .get_qos2_state(&connect.client_id, packet_id)
+                    == Some(Qos2State::Recorded)
+                {
+                    self.send_packet(&Packet::PubRel(PubRel {
+                        packet_id,
+                        reason_code: PubRelReasonCode::Success,
+                        properties: PubRelProperties::default(),
+                    }))
+                    .await?;
+                    continue;
+                }
+
+                publish.dup = true;
                 self.send_packet(&Packet::Publish(publish)).await?;
             }
         } else {
-            for s in &self.state.config.subscriptions {
-                let filter = match filter_util::parse_filter(&s.path) {
+            for s in &self.config.subscriptions {
+                let path = match &self.remote_addr.mountpoint {
+                    Some(mountpoint) => Cow::Owned(filter_util::mount_filter(mountpoint, &s.path)),
+                    None => Cow::Borrowed(&*s.path),
+                };
+                let filter = match filter_util::parse_filter(&path) {
                     Some(filter) => filter,
                     None => {
                         tracing::warn!(
-                            filter = %s.path,
+                            filter = %path,
                             "failed to parse proxy subscription filter",
                         );
                         continue;
@@ -427,12 +592,10 @@ where
             }
         };
 
-        self.state
-            .service_metrics
-            .inc_pub_bytes_received(publish.payload.len());
-        self.state.service_metrics.inc_pub_msgs_received(1);
+        self.metrics.inc_pub_bytes_received(publish.payload.len());
+        self.metrics.inc_pub_msgs_received(1);
 
-        if matches!(publish.properties.topic_alias, Some(client) if client.get() > self.state.config.max_topic_alias)
+        if matches!(publish.properties.topic_alias, Some(client) if client.get() > self.config.max_topic_alias)
         {
             // A Topic Alias value of 0 or greater than the Maximum Topic Alias is a Protocol Error, the
             // receiver uses DISCONNECT with Reason Code of 0x94 (Topic Alias invalid) as described in section 4.13.
@@ -472,7 +635,7 @@ where
             ));
         }
 
-        if publish.retain && !self.state.config.retain_available {
+        if publish.retain && !self.config.retain_available {
             // If the Server included Retain Available in its CONNACK response to a Client
             // with its value set to 0 and it receives a PUBLISH packet with the RETAIN flag is
             // set to 1, then it uses the DISCONNECT Reason Code of 0x9A (Retain not supported) as
@@ -515,46 +678,84 @@ where
             }
         };
 
-        let retain = publish.retain;
-        let packet_id = publish.packet_id;
-
-        // check acl
-        self.check_acl(Action::Publish, &publish.topic).await?;
+        if let Some(mountpoint) = &self.remote_addr.mountpoint {
+            publish.topic = format!("{}{}", mountpoint, publish.topic).into();
+        }
 
-        // rewrite
-        self.state.rewrite(&mut publish.topic);
+        let qos = publish.qos;
+        let packet_id = publish.packet_id;
 
-        // create message
-        let mut msg = Message::from_publish(&publish).with_from_client_id(client_id.clone());
-        if let Some(uid) = &self.uid {
-            msg = msg.with_from_uid(uid.clone());
-        }
+        if let (Qos::ExactlyOnce, Some(packet_id)) = (qos, packet_id) {
+            if self
+                .state
+                .storage
+                .has_uncompleted_message(&client_id, packet_id)
+            {
+                // Until it has received the corresponding PUBREL packet, the receiver MUST acknowledge
+                // any subsequent PUBLISH packet with the same Packet Identifier by sending a PUBREC.
+                // It MUST NOT cause duplicate messages to be delivered to any onward recipients in
+                // this case [MQTT-4.3.3-10].
+                if publish.dup {
+                    return self
+                        .send_packet(&Packet::PubRec(PubRec {
+                            packet_id,
+                            reason_code: PubRecReasonCode::Success,
+                            properties: PubRecProperties::default(),
+                        }))
+                        .await;
+                }
 
-        if retain {
-            // update retained message
-            self.state.storage.update_retained_message(msg.clone());
+                return if self.codec.protocol_level() == ProtocolLevel::V5 {
+                    self.send_packet(&Packet::PubRec(PubRec {
+                        packet_id,
+                        reason_code: PubRecReasonCode::PacketIdentifierInUse,
+                        properties: PubRecProperties::default(),
+                    }))
+                    .await
+                } else {
+                    Err(Error::server_disconnect(
+                        DisconnectReasonCode::ProtocolError,
+                    ))
+                };
+            }
         }
 
-        for (_, plugin) in &self.state.plugins {
-            plugin
-                .on_message_publish(
-                    self.client_id.as_ref().unwrap(),
-                    self.uid.as_deref(),
-                    msg.topic(),
-                    msg.qos(),
-                    msg.is_retain(),
-                    msg.payload().clone(),
-                )
-                .await;
-        }
+        let publisher = Publisher {
+            remote_addr: &self.remote_addr,
+            client_id: Some(&client_id),
+            uid: self.uid.as_deref(),
+            level: Some(self.codec.protocol_level()),
+            maximum_qos: self.config.maximum_qos,
+        };
+        let msg = match self
+            .state
+            .accept_publish(&publisher, Message::from_publish(&publish))
+            .await
+            .map_err(|_| Error::server_disconnect(DisconnectReasonCode::UnspecifiedError))?
+        {
+            PublishOutcome::Accepted(msg) => msg,
+            PublishOutcome::QosNotSupported => {
+                return Err(Error::server_disconnect(
+                    DisconnectReasonCode::QoSNotSupported,
+                ));
+            }
+            PublishOutcome::NotAuthorized => {
+                return Err(Error::server_disconnect(
+                    DisconnectReasonCode::NotAuthorized,
+                ));
+            }
+            PublishOutcome::Unrouted(reason_code) => {
+                return self.ack_unrouted_publish(qos, packet_id, reason_code).await;
+            }
+        };
 
         // do publish
         match msg.qos() {
             Qos::AtMostOnce => {
-                self.state.storage.deliver(std::iter::once(msg));
+                self.state.storage.deliver(vec![msg]);
             }
             Qos::AtLeastOnce => {
-                self.state.storage.deliver(std::iter::once(msg));
+                self.state.storage.deliver(vec![msg]);
                 self.send_packet(&Packet::PubAck(PubAck {
                     packet_id: packet_id.unwrap(),
                     reason_code: PubAckReasonCode::Success,
@@ -572,25 +773,9 @@ where
 
                 let packet_id = packet_id.unwrap();
 
-                if self
-                    .uncompleted_messages
-                    .insert(packet_id, msg.clone())
-                    .is_some()
-                {
-                    return if self.codec.protocol_level() == ProtocolLevel::V5 {
-                        self.send_packet(&Packet::PubRec(PubRec {
-                            packet_id,
-                            reason_code: PubRecReasonCode::PacketIdentifierInUse,
-                            properties: PubRecProperties::default(),
-                        }))
-                        .await?;
-                        Ok(())
-                    } else {
-                        Err(Error::server_disconnect(
-                            DisconnectReasonCode::ProtocolError,
-                        ))
-                    };
-                }
+                self.state
+                    .storage
+                    .add_uncompleted_message(&client_id, packet_id, Some(msg));
 
                 self.receive_in_quota -= 1;
                 self.send_packet(&Packet::PubRec(PubRec {
@@ -605,6 +790,50 @@ where
         Ok(())
     }
 
+    /// Acknowledges a message that is dropped or rejected by the plugins.
+    async fn ack_unrouted_publish(
+        &mut self,
+        qos: Qos,
+        packet_id: Option<NonZeroU16>,
+        reason_code: PubAckReasonCode,
+    ) -> Result<(), Error> {
+        let reason_code = if self.codec.protocol_level() == ProtocolLevel::V5 {
+            reason_code
+        } else {
+            PubAckReasonCode::Success
+        };
+
+        match qos {
+            Qos::AtMostOnce => Ok(()),
+            Qos::AtLeastOnce => {
+                self.send_packet(&Packet::PubAck(PubAck {
+                    packet_id: packet_id.unwrap(),
+                    reason_code,
+                    properties: PubAckProperties::default(),
+                }))
+                .await
+            }
+            Qos::ExactlyOnce => {
+                let packet_id = packet_id.unwrap();
+                // the flow ends with a PUBREC that has an error reason code, otherwise the session
+                // waits for PUBREL like for a routed message
+                if reason_code.is_success() {
+                    let client_id = self.client_id.as_ref().unwrap();
+                    self.state
+                        .storage
+                        .add_uncompleted_message(client_id, packet_id, None);
+                }
+                self.send_packet(&Packet::PubRec(PubRec {
+                    packet_id,
+                    reason_code: PubRecReasonCode::try_from(u8::from(reason_code))
+                        .unwrap_or(PubRecReasonCode::UnspecifiedError),
+                    properties: PubRecProperties::default(),
+                }))
+                .await
+            }
+        }
+    }
+
     async fn handle_pub_ack(&mut self, pub_ack: PubAck) -> Result<(), Error> {
         let client_id = match &self.client_id {
             Some(client_id) => client_id,
@@ -629,7 +858,7 @@ where
         {
             Some(_) => {
                 self.receive_out_quota += 1;
-                Ok(())
+                self.handle_notified().await
             }
             None => Err(Error::server_disconnect(
                 DisconnectReasonCode::ProtocolError,
@@ -647,21 +876,20 @@ where
             }
         };
 
-        match self.inflight_qos2_messages.get_mut(&pub_rec.packet_id) {
-            Some(state) if *state != Qos2State::Published => {
-                return Err(Error::server_disconnect(
-                    DisconnectReasonCode::ProtocolError,
-                ));
-            }
-            Some(state) => *state = Qos2State::Recorded,
-            None => {
-                return Err(Error::server_disconnect(
-                    DisconnectReasonCode::ProtocolError,
-                ));
-            }
+        if !self
+            .state
+            .storage
+            .set_qos2_recorded(client_id, pub_rec.packet_id)
+        {
+            return Err(Error::server_disconnect(
+                DisconnectReasonCode::ProtocolError,
+            ));
         }
 
         if !pub_rec.reason_code.is_success() {
+            self.state
+                .storage
+                .remove_qos2_state(client_id, pub_rec.packet_id);
             if self
                 .state
                 .storage
@@ -707,26 +935,38 @@ where
     }
 
     async fn handle_pub_rel(&mut self, pub_rel: PubRel) -> Result<(), Error> {
-        if self.client_id.is_none() {
-            return Err(Error::server_disconnect(
-                DisconnectReasonCode::ProtocolError,
-            ));
-        }
+        let client_id = match &self.client_id {
+            Some(client_id) => client_id,
+            None => {
+                return Err(Error::server_disconnect(
+                    DisconnectReasonCode::ProtocolError,
+                ))
+            }
+        };
 
-        match self.uncompleted_messages.remove(&pub_rel.packet_id) {
+        match self
+            .state
+            .storage
+            .take_uncompleted_message(client_id, pub_rel.packet_id)
+        {
             Some(msg) => {
                 if !pub_rel.reason_code.is_success() {
                     return Ok(());
                 }
 
-                self.state.storage.deliver(std::iter::once(msg));
+                if let Some(msg) = msg {
+                    self.state.storage.deliver(vec![msg]);
+                }
                 self.send_packet(&Packet::PubComp(PubComp {
                     packet_id: pub_rel.packet_id,
                     reason_code: PubCompReasonCode::Success,
                     properties: PubCompProperties::default(),
                 }))
                 .await?;
-                self.receive_in_quota += 1;
+
+                // Messages received before a reconnect do not count against the quota of this
+                // connection.
+                self.receive_in_quota = (self.receive_in_quota + 1).min(self.receive_in_max);
             }
             None => {
                 if self.codec.protocol_level() == ProtocolLevel::V5 {
@@ -757,10 +997,12 @@ where
             }
         };
 
-        if !matches!(
-            self.inflight_qos2_messages.remove(&pub_comp.packet_id),
-            Some(Qos2State::Recorded)
-        ) {
+        if self
+            .state
+            .storage
+            .remove_qos2_state(client_id, pub_comp.packet_id)
+            != Some(Qos2State::Recorded)
+        {
             return Err(Error::server_disconnect(
                 DisconnectReasonCode::ProtocolError,
             ));
@@ -805,9 +1047,14 @@ where
         };
 
         let mut reason_codes = Vec::with_capacity(subscribe.filters.len());
+        let mut reason_strings = Vec::new();
 
         for s in &subscribe.filters {
-            let filter = match filter_util::parse_filter(&s.path) {
+            let path = match &self.remote_addr.mountpoint {
+                Some(mountpoint) => Cow::Owned(filter_util::mount_filter(mountpoint, &s.path)),
+                None => Cow::Borrowed(&*s.path),
+            };
+            let filter = match filter_util::parse_filter(&path) {
                 Some(filter) => filter,
                 None => {
                     reason_codes.push(SubscribeReasonCode::TopicFilterInvalid);
@@ -822,19 +1069,54 @@ where
                 ));
             }
 
-            if !self.state.config.wildcard_subscription_available
+            if !self.config.wildcard_subscription_available
                 && filter_util::has_wildcards(filter.path)
             {
                 reason_codes.push(SubscribeReasonCode::WildcardSubscriptionsNotSupported);
                 continue;
             }
 
+            let request = AclRequest {
+                remote_addr: &self.remote_addr,
+                client_id: Some(&client_id),
+                uid: self.uid.as_deref(),
+                level: Some(self.codec.protocol_level()),
+                action: Action::Subscribe,
+                topic: filter.path,
+                qos: s.qos,
+                retain: false,
+            };
+
             // check acl
-            self.check_acl(Action::Subscribe, &filter.path).await?;
+            if !self
+                .state
+                .check_acl(&request)
+                .await
+                .map_err(|_| Error::server_disconnect(DisconnectReasonCode::UnspecifiedError))?
+            {
+                reason_codes.push(SubscribeReasonCode::NotAuthorized);
+                continue;
+            }
+
+            let authorization = self
+                .state
+                .authorize_subscribe(&request)
+                .await
+                .map_err(|_| Error::server_disconnect(DisconnectReasonCode::UnspecifiedError))?;
+            if let Some(reason_string) = authorization.reason_string {
+                reason_strings.push(format!("{}: {}", s.path, reason_string));
+            }
+            let max_qos = match authorization.reason_code.qos() {
+                Some(max_qos) => max_qos,
+                None => {
+                    reason_codes.push(authorization.reason_code);
+                    continue;
+                }
+            };
 
-            let qos = s.qos.min(self.state.config.maximum_qos);
+            let qos = s.qos.min(self.config.maximum_qos).min(max_qos);
 
-            for (_, plugin) in &self.state.plugins {
+            for (_, plugin) in self.state.plugins().iter() {
                 plugin
                     .on_session_subscribed(
                         self.client_id.as_ref().unwrap(),
@@ -854,7 +1136,7 @@ where
             self.state.storage.subscribe(
                 &client_id,
                 filter,
-                s.qos,
+                qos,
                 s.no_local,
                 s.retain_as_published,
                 s.retain_handling,
@@ -865,7 +1147,11 @@ where
         self.send_packet(&Packet::SubAck(SubAck {
             packet_id: subscribe.packet_id,
             reason_codes,
-            properties: SubAckProperties::default(),
+            properties: SubAckProperties {
+                reason_string: (!reason_strings.is_empty())
+                    .then(|| reason_strings.join("; ").into()),
+                ..SubAckProperties::default()
+            },
         }))
         .await?;
 
@@ -884,7 +1170,11 @@ where
         let mut reason_codes = Vec::new();
 
         for path in unsubscribe.filters {
-            let filter = match filter_util::parse_filter(&*path) {
+            let mounted_path = match &self.remote_addr.mountpoint {
+                Some(mountpoint) => Cow::Owned(filter_util::mount_filter(mountpoint, &path)),
+                None => Cow::Borrowed(&*path),
+            };
+            let filter = match filter_util::parse_filter(&mounted_path) {
                 Some(filter) => filter,
                 None => {
                     reason_codes.push(UnsubAckReasonCode::TopicFilterInvalid);
@@ -892,7 +1182,7 @@ where
                 }
             };
 
-            for (_, plugin) in &self.state.plugins {
+            for (_, plugin) in self.state.plugins().iter() {
                 plugin
                     .on_session_unsubscribed(
                         self.client_id.as_ref().unwrap(),
@@ -936,18 +1226,178 @@ where
         Err(Error::ClientDisconnect(disconnect))
     }
 
+    async fn handle_auth(&mut self, auth: Auth) -> Result<(), Error> {
+        // If the Authentication Method is not the same as the Authentication Method used in the
+        // CONNECT packet, it is a Protocol Error [MQTT-4.12.0-5].
+        if auth.properties.authentication_method.is_none()
+            || auth.properties.authentication_method != self.auth_method
+        {
+            return Err(Error::server_disconnect(
+                DisconnectReasonCode::ProtocolError,
+            ));
+        }
+
+        match auth.reason_code {
+            AuthReasonCode::ContinueAuthentication if self.enhanced_auth.is_some() => {
+                self.enhanced_auth_step(auth.properties.authentication_data)
+                    .await
+            }
+            AuthReasonCode::ReAuthenticate
+                if self.client_id.is_some() && self.enhanced_auth.is_none() =>
+            {
+                let client_id = self.client_id.clone().unwrap();
+                let method = self.auth_method.clone().unwrap();
+                let authenticator = match self.create_enhanced_auth(&client_id, &method).await? {
+                    Some(authenticator) => authenticator,
+                    None => {
+                        return Err(Error::server_disconnect(
+                            DisconnectReasonCode::BadAuthenticationMethod,
+                        ))
+                    }
+                };
+                self.enhanced_auth = Some(EnhancedAuthExchange {
+                    authenticator,
+                    connect: None,
+                });
+                self.enhanced_auth_step(auth.properties.authentication_data)
+                    .await
+            }
+            _ => Err(Error::server_disconnect(
+                DisconnectReasonCode::ProtocolError,
+            )),
+        }
+    }
+
+    async fn create_enhanced_auth(
+        &self,
+        client_id: &str,
+        method: &str,
+    ) -> Result<Option<Box<dyn EnhancedAuth>>, Error> {
+        for (name, plugin) in self.state.plugins().iter() {
+            match plugin
+                .enhanced_auth(&self.remote_addr, client_id, method)
+                .await
+            {
+                Ok(Some(authenticator)) => return Ok(Some(authenticator)),
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::enhanced_auth",
+                    );
+                    return Err(Error::internal_error(err));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn enhanced_auth_step(&mut self, data: Option<Bytes>) -> Result<(), Error> {
+        let exchange = match &mut self.enhanced_auth {
+            Some(exchange) => exchange,
+            None => {
+                return Err(Error::server_disconnect(
+                    DisconnectReasonCode::ProtocolError,
+                ))
+            }
+        };
+
+        let res = match exchange.authenticator.step(data).await {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!(
+                    error = %err,
+                    "failed to call plugin::enhanced_auth",
+                );
+                return Err(Error::internal_error(err));
+            }
+        };
+
+        match res {
+            EnhancedAuthResult::Continue(data) => {
+                self.send_packet(&Packet::Auth(Auth {
+                    reason_code: AuthReasonCode::ContinueAuthentication,
+                    properties: AuthProperties {
+                        authentication_method: self.auth_method.clone(),
+                        authentication_data: Some(data),
+                        ..AuthProperties::default()
+                    },
+                }))
+                .await
+            }
+            EnhancedAuthResult::Success { uid, data } => {
+                let exchange = self.enhanced_auth.take().unwrap();
+                match exchange.connect {
+                    Some((connect, mut conn_ack_properties)) => {
+                        conn_ack_properties.authentication_method = self.auth_method.clone();
+                        conn_ack_properties.authentication_data = data;
+                        self.finish_connect(connect, conn_ack_properties, Some(uid.into()))
+                            .await
+                    }
+                    None => {
+                        self.uid = Some(uid.into());
+                        self.send_packet(&Packet::Auth(Auth {
+                            reason_code: AuthReasonCode::Success,
+                            properties: AuthProperties {
+                                authentication_method: self.auth_method.clone(),
+                                authentication_data: data,
+                                ..AuthProperties::default()
+                            },
+                        }))
+                        .await
+                    }
+                }
+            }
+            EnhancedAuthResult::Failure => {
+                let exchange = self.enhanced_auth.take().unwrap();
+                if exchange.connect.is_some() {
+                    self.send_packet(&Packet::ConnAck(ConnAck {
+                        session_present: false,
+                        reason_code: ConnectReasonCode::NotAuthorized,
+                        properties: ConnAckProperties::default(),
+                    }))
+                    .await?;
+                    Err(Error::ServerDisconnect(None))
+                } else {
+                    Err(Error::server_disconnect(
+                        DisconnectReasonCode::NotAuthorized,
+                    ))
+                }
+            }
+        }
+    }
+
     async fn handle_control(&mut self, control: Control) -> Result<(), Error> {
         match control {
             Control::SessionTakenOver => {
                 self.client_id = None;
-                self.state.service_metrics.dec_connection_count(1);
+                self.metrics.dec_connection_count(1);
                 Err(Error::SessionTakenOver)
             }
+            Control::Kick { remove_session } => {
+                if remove_session {
+                    // the session expires immediately after the connection is closed
+                    self.session_expiry_interval = 0;
+                }
+                Err(Error::server_disconnect(
+                    DisconnectReasonCode::AdministrativeAction,
+                ))
+            }
+            Control::ServerShuttingDown => Err(Error::server_disconnect(
+                DisconnectReasonCode::ServerShuttingDown,
+            )),
         }
     }
 
     async fn handle_notified(&mut self) -> Result<(), Error> {
         if let Some(client_id) = self.client_id.clone() {
+            if self.state.storage.take_queue_overflowed(&client_id) {
+                return Err(Error::server_disconnect(
+                    DisconnectReasonCode::QuotaExceeded,
+                ));
+            }
+
             if self.receive_out_quota == 0 {
                 return Ok(());
             }
@@ -969,18 +1419,69 @@ where
         Ok(())
     }
 
-    async fn delive(&mut self, msg: Message) -> Result<(), Error> {
+    async fn delive(&mut self, mut msg: Message) -> Result<(), Error> {
         let client_id = match self.client_id.clone() {
             Some(client_id) => client_id,
             None => return Ok(()),
         };
 
+        // the message may be dispatched to another member of the share group, so it is kept as
+        // routed, before the ACL and the plugins of this client apply
+        let shared_msg = msg.share_name().is_some().then(|| msg.clone());
+
+        if !self.check_deliver_acl(&msg).await? {
+            // another member of the share group may be allowed to receive the message
+            if let Some(shared_msg) = shared_msg {
+                if self
+                    .state
+                    .storage
+                    .redispatch_refused_message(&client_id, shared_msg)
+                {
+                    return Ok(());
+                }
+            }
+            self.state.service_metrics.inc_msg_dropped(1);
+            return Ok(());
+        }
+
+        let topic = msg.topic().clone();
+        if !self
+            .state
+            .intercept_delivery(&self.remote_addr, &client_id, self.uid.as_deref(), &mut msg)
+            .await
+            .map_err(|_| Error::server_disconnect(DisconnectReasonCode::UnspecifiedError))?
+        {
+            self.state.service_metrics.inc_msg_dropped(1);
+            return Ok(());
+        }
+
+        // the subscriber must also be allowed to receive the topic chosen by the plugins
+        if *msg.topic() != topic && !self.check_deliver_acl(&msg).await? {
+            self.state.service_metrics.inc_msg_dropped(1);
+            return Ok(());
+        }
+
         let mut publish = match msg.to_publish_and_update_expiry_interval() {
             Some(publish) => publish,
             None => return Ok(()),
         };
+        if let Some(mountpoint) = &self.remote_addr.mountpoint {
+            match publish.topic.strip_prefix(&**mountpoint) {
+                Some(topic) => publish.topic = topic.into(),
+                None => {
+                    tracing::warn!(
+                        remote_addr = %self.remote_addr,
+                        client_id = %client_id,
+                        topic = %publish.topic,
+                        "the topic is outside the mountpoint of the subscriber",
+                    );
+                    self.state.service_metrics.inc_msg_dropped(1);
+                    return Ok(());
+                }
+            }
+        }
 
-        for (_, plugin) in &self.state.plugins {
+        for (_, plugin) in self.state.plugins().iter() {
             plugin
                 .on_message_delivered(
                     self.client_id.as_ref().unwrap(),
@@ -995,7 +1496,7 @@ where
                 .await;
         }
 
-        self.state.service_metrics.inc_pub_msgs_sent(1);
+        self.metrics.inc_pub_msgs_sent(1);
         match publish.qos {
             Qos::AtMostOnce => self.send_packet(&Packet::Publish(publish)).await,
             Qos::AtLeastOnce | Qos::ExactlyOnce => {
@@ -1014,9 +1515,7 @@ where
                 );
                 self.state
                     .storage
-                    .add_inflight_pub_packet(&client_id, publish.clone());
-                self.inflight_qos2_messages
-                    .insert(packet_id, Qos2State::Published);
+                    .add_inflight_pub_packet(&client_id, publish.clone(), shared_msg);
                 self.send_packet(&Packet::Publish(publish)).await?;
                 Ok(())
             }
@@ -1030,15 +1529,20 @@ pub async fn client_loop(
     writer: impl AsyncWrite + Send + Unpin,
     remote_addr: RemoteAddr,
 ) {
-    state.service_metrics.inc_socket_connections(1);
+    let metrics = ConnectionMetrics::new(state.service_metrics.clone(), &remote_addr);
+    metrics.inc_socket_connections(1);
 
     let (control_sender, mut control_receiver) = mpsc::unbounded_channel();
     let mut connection = Connection {
         state: state.clone(),
+        config: state.config(),
+        metrics,
         remote_addr,
         client_id: None,
         control_sender,
         uid: None,
+        auth_method: None,
+        enhanced_auth: None,
         notify: Arc::new(Notify::new()),
         codec: Codec::new(reader, writer),
         session_expiry_interval: 0,
@@ -1048,12 +1552,11 @@ pub async fn client_loop(
         receive_out_quota: 0,
         max_topic_alias: 0,
         topic_alias: FnvHashMap::default(),
+        deliver_acl_cache: DeliverAclCache::default(),
         keep_alive: 60,
         last_active: Instant::now(),
         last_will: None,
         packet_id_allocator: PacketIdAllocator::default(),
-        inflight_qos2_messages: FnvHashMap::default(),
-        uncompleted_messages: FnvHashMap::default(),
     };
     let mut keep_alive_interval = tokio::time::interval(Duration::from_secs(1));
 
@@ -1073,8 +1576,8 @@ pub async fn client_loop(
             res = connection.codec.decode() => {
                 match res {
                     Ok(Some((packet, packet_size))) => {
-                        connection.state.service_metrics.inc_bytes_received(packet_size);
-                        connection.state.service_metrics.inc_msgs_received(1);
+                        connection.metrics.inc_bytes_received(packet_size);
+                        connection.metrics.inc_msgs_received(1);
                         connection.last_active = Instant::now();
                         tracing::debug!(
                             remote_addr = %connection.remote_addr,
@@ -1143,6 +1646,15 @@ pub async fn client_loop(
                             ).await.ok();
                             break;
                         },
+                        Err(Error::ServerDisconnect(Some(disconnect))) => {
+                            tracing::debug!(
+                                remote_addr = %connection.remote_addr,
+                                reason_code = ?disconnect.reason_code,
+                                "server disconnect",
+                            );
+                            connection.send_packet(&Packet::Disconnect(disconnect)).await.ok();
+                            break;
+                        }
                         Err(err) => {
                             tracing::debug!(
                                 remote_addr = %connection.remote_addr,
@@ -1155,13 +1667,25 @@ pub async fn client_loop(
                 }
             }
             _ = connection.notify.notified() => {
-                if let Err(err) = connection.handle_notified().await {
-                    tracing::debug!(
-                        remote_addr = %connection.remote_addr,
-                        error = %err,
-                        "error",
-                    );
-                    break;
+                match connection.handle_notified().await {
+                    Ok(()) => {}
+                    Err(Error::ServerDisconnect(Some(disconnect))) => {
+                        tracing::debug!(
+                            remote_addr = %connection.remote_addr,
+                            reason_code = ?disconnect.reason_code,
+                            "server disconnect",
+                        );
+                        connection.send_packet(&Packet::Disconnect(disconnect)).await.ok();
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::debug!(
+                            remote_addr = %connection.remote_addr,
+                            error = %err,
+                            "error",
+                        );
+                        break;
+                    }
                 }
             }
         }
@@ -1174,18 +1698,18 @@ pub async fn client_loop(
             .write()
             .await
             .remove(&**client_id);
-        connection.state.service_metrics.dec_connection_count(1);
+        connection.metrics.dec_connection_count(1);
         connection
             .state
             .storage
             .disconnect_session(&client_id, connection.session_expiry_interval);
 
-        for (_, plugin) in &connection.state.plugins {
+        for (_, plugin) in connection.state.plugins().iter() {
             plugin
                 .on_client_disconnected(client_id, connection.uid.as_deref())
                 .await;
         }
     }
 
-    state.service_metrics.dec_socket_connections(1);
+    connection.metrics.dec_socket_connections(1);
 }
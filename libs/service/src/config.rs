@@ -1,5 +1,7 @@
+use std::collections::HashMap;
+
 use codec::{Qos, SubscribeFilter};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
 pub struct RewriteConfig {
@@ -7,6 +9,73 @@ pub struct RewriteConfig {
     pub write: String,
 }
 
+/// What to do when a message is added to a full session queue.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    #[default]
+    DropNewest,
+    DropQos0First,
+    Disconnect,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QueueLimits {
+    /// Maximum number of queued messages.
+    pub max_len: Option<usize>,
+    /// Maximum number of queued payload bytes.
+    pub max_bytes: Option<usize>,
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientQueueLimits {
+    /// Regular expression matched against the client id.
+    pub client_id: String,
+    #[serde(flatten)]
+    pub limits: QueueLimits,
+}
+
+/// How a message is dispatched to the members of a shared subscription group.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedSubscriptionStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// Keeps sending to the same member while it is online.
+    Sticky,
+    /// Selects the member by the hash of the publisher client id.
+    HashClientId,
+    /// Selects the member by the hash of the topic.
+    HashTopic,
+    /// Selects the member with the fewest queued and inflight messages.
+    LeastQueued,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SharedSubscriptionConfig {
+    #[serde(default)]
+    pub strategy: SharedSubscriptionStrategy,
+    /// Strategies of individual share groups, keyed by the share name.
+    #[serde(default)]
+    pub groups: HashMap<String, SharedSubscriptionStrategy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    #[default]
+    Memory,
+    Disk {
+        path: String,
+        #[serde(default = "default_snapshot_interval")]
+        snapshot_interval: usize,
+    },
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ServiceConfig {
     #[serde(default = "default_metrics_update_interval")]
@@ -31,12 +100,44 @@ pub struct ServiceConfig {
     pub subscriptions: Vec<SubscribeFilter>,
     #[serde(default)]
     pub rewrites: Vec<RewriteConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub queue_limits: QueueLimits,
+    #[serde(default)]
+    pub client_queue_limits: Vec<ClientQueueLimits>,
+    #[serde(default)]
+    pub shared_subscriptions: SharedSubscriptionConfig,
+    /// Number of seconds to wait for the clients to disconnect when the server shuts down.
+    #[serde(default = "default_shutdown_drain_period")]
+    pub shutdown_drain_period: u64,
+    /// Checks the ACL with `Action::Deliver` before a message is sent to a subscriber.
+    ///
+    /// A message of a shared subscription that is refused is dispatched to another member of the
+    /// share group.
+    #[serde(default)]
+    pub deliver_acl: bool,
+    /// Number of seconds that a connection caches the result of a deliver ACL check.
+    #[serde(default = "default_deliver_acl_cache_ttl")]
+    pub deliver_acl_cache_ttl: u64,
+}
+
+fn default_snapshot_interval() -> usize {
+    10000
 }
 
 fn default_metrics_update_interval() -> u64 {
     5
 }
 
+fn default_shutdown_drain_period() -> u64 {
+    5
+}
+
+fn default_deliver_acl_cache_ttl() -> u64 {
+    60
+}
+
 fn default_max_keep_alive() -> u16 {
     30
 }
@@ -83,6 +184,13 @@ impl Default for ServiceConfig {
             wildcard_subscription_available: default_wildcard_subscription_available(),
             subscriptions: Vec::new(),
             rewrites: Vec::new(),
+            storage: StorageConfig::default(),
+            queue_limits: QueueLimits::default(),
+            client_queue_limits: Vec::new(),
+            shared_subscriptions: SharedSubscriptionConfig::default(),
+            shutdown_drain_period: default_shutdown_drain_period(),
+            deliver_acl: false,
+            deliver_acl_cache_ttl: default_deliver_acl_cache_ttl(),
         }
     }
 }
@@ -64,6 +64,42 @@ pub fn parse_filter(filter: &str) -> Option<Filter> {
     }
 }
 
+/// Prepends `mountpoint` to the path of a filter, the share name of a shared subscription is kept
+/// in front.
+pub fn mount_filter(mountpoint: &str, filter: &str) -> String {
+    match filter
+        .strip_prefix("$share/")
+        .and_then(|tail| tail.split_once('/'))
+    {
+        Some((share_name, path)) => format!("$share/{}/{}{}", share_name, mountpoint, path),
+        None => format!("{}{}", mountpoint, filter),
+    }
+}
+
+/// Returns `true` if `filter` matches `topic`.
+///
+/// `topic` can also be a filter, then it returns `true` if `filter` matches all the topics that
+/// `topic` matches.
+pub fn matches_topic(filter: &str, topic: &str) -> bool {
+    // The Server MUST NOT match Topic Filters starting with a wildcard character (# or +) with
+    // Topic Names beginning with a $ character [MQTT-4.7.2-1].
+    if topic.starts_with('$') && filter.starts_with(&['+', '#'][..]) {
+        return false;
+    }
+
+    let mut topic_segments = topic.split('/');
+    for segment in filter.split('/') {
+        match (segment, topic_segments.next()) {
+            ("#", _) => return true,
+            ("+", Some(topic_segment)) if topic_segment != "#" => {}
+            (segment, Some(topic_segment))
+                if segment == topic_segment && !has_wildcards(segment) => {}
+            _ => return false,
+        }
+    }
+    topic_segments.next().is_none()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +142,34 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_mount_filter() {
+        assert_eq!(mount_filter("site/", "a/+/c"), "site/a/+/c");
+        assert_eq!(mount_filter("site/", "#"), "site/#");
+        assert_eq!(
+            mount_filter("site/", "$share/abc/a/#"),
+            "$share/abc/site/a/#"
+        );
+    }
+
+    #[test]
+    fn test_matches_topic() {
+        assert!(matches_topic("a/b/c", "a/b/c"));
+        assert!(!matches_topic("a/b/c", "a/b"));
+        assert!(!matches_topic("a/b", "a/b/c"));
+        assert!(matches_topic("a/+/c", "a/b/c"));
+        assert!(!matches_topic("a/+", "a/b/c"));
+        assert!(matches_topic("a/#", "a"));
+        assert!(matches_topic("a/#", "a/b/c"));
+        assert!(matches_topic("#", "a/b/c"));
+        assert!(!matches_topic("#", "$SYS/a"));
+        assert!(!matches_topic("+/a", "$SYS/a"));
+        assert!(matches_topic("$SYS/#", "$SYS/a"));
+
+        assert!(matches_topic("a/#", "a/+/c"));
+        assert!(matches_topic("a/+/c", "a/+/c"));
+        assert!(!matches_topic("a/+", "a/#"));
+        assert!(!matches_topic("a/b/c", "a/+/c"));
+    }
 }
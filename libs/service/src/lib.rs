@@ -4,7 +4,6 @@
 mod client_loop;
 mod config;
 mod error;
-mod filter_util;
 mod message;
 mod metrics;
 mod rewrite;
@@ -12,13 +11,18 @@ mod state;
 mod storage;
 mod sys_topics;
 mod trie;
+mod watched_file;
 
+pub mod filter_util;
 pub mod plugin;
 
-pub use client_loop::{client_loop, RemoteAddr};
+pub use client_loop::{client_loop, CertIdentity, PeerCred, RemoteAddr};
 pub use codec;
-pub use config::ServiceConfig;
+pub use config::{ServiceConfig, StorageConfig};
 pub use error::Error;
+pub use filter_util::valid_topic;
 pub use message::Message;
 pub use metrics::Metrics;
-pub use state::ServiceState;
+pub use state::{ListenerMetrics, PublishOutcome, ServiceState};
+pub use storage::{SessionInfo, SubscriptionInfo};
+pub use watched_file::WatchedFile;
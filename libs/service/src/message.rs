@@ -15,6 +15,8 @@ pub struct Message {
     payload: Bytes,
     retain: bool,
     properties: PublishProperties,
+    share_name: Option<ByteString>,
+    refused_by: Vec<ByteString>,
 }
 
 impl Message {
@@ -29,6 +31,8 @@ impl Message {
             payload: payload.into(),
             retain: false,
             properties: PublishProperties::default(),
+            share_name: None,
+            refused_by: Vec::new(),
         }
     }
 
@@ -38,6 +42,18 @@ impl Message {
         self
     }
 
+    #[inline]
+    pub fn with_payload(mut self, payload: impl Into<Bytes>) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    #[inline]
+    pub fn with_topic(mut self, topic: impl Into<ByteString>) -> Self {
+        self.topic = topic.into();
+        self
+    }
+
     #[inline]
     pub fn with_retain(mut self, retain: bool) -> Self {
         self.retain = retain;
@@ -56,6 +72,19 @@ impl Message {
         self
     }
 
+    #[inline]
+    pub fn with_share_name(mut self, share_name: impl Into<ByteString>) -> Self {
+        self.share_name = Some(share_name.into());
+        self
+    }
+
+    /// Records that a member of the share group refused the message.
+    #[inline]
+    pub fn with_refused_by(mut self, client_id: impl Into<ByteString>) -> Self {
+        self.refused_by.push(client_id.into());
+        self
+    }
+
     #[inline]
     pub fn from_client_id(&self) -> Option<&ByteString> {
         self.from_client_id.as_ref()
@@ -86,6 +115,19 @@ impl Message {
         &self.properties
     }
 
+    /// The share name of the shared subscription through which the message was queued.
+    #[inline]
+    pub fn share_name(&self) -> Option<&ByteString> {
+        self.share_name.as_ref()
+    }
+
+    /// The members of the share group that refused the message, it is not dispatched to them
+    /// again.
+    #[inline]
+    pub fn refused_by(&self) -> &[ByteString] {
+        &self.refused_by
+    }
+
     #[inline]
     pub fn is_retain(&self) -> bool {
         self.retain
@@ -7,6 +7,7 @@ use crate::state::ServiceMetrics;
 use crate::storage::StorageMetrics;
 
 #[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
 pub struct MetricsLoad {
     pub min1: f64,
     pub min5: f64,
@@ -14,6 +15,7 @@ pub struct MetricsLoad {
 }
 
 #[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
 pub struct Metrics {
     pub uptime: u64,
     pub bytes_received: usize,
@@ -1,15 +1,16 @@
 use std::sync::Arc;
 
-use codec::{ProtocolLevel, Qos};
+use bytestring::ByteString;
+use codec::{ProtocolLevel, PubAckReasonCode, PublishProperties, Qos, SubscribeReasonCode};
 use serde_yaml::Value;
 
-use crate::RemoteAddr;
+use crate::{Message, RemoteAddr};
 use bytes::Bytes;
 
 pub type PluginResult<T> = anyhow::Result<T>;
 
 #[async_trait::async_trait]
-pub trait PluginFactory: 'static {
+pub trait PluginFactory: Send + Sync + 'static {
     fn name(&self) -> &'static str;
 
     async fn create(&self, config: Value) -> PluginResult<Arc<dyn Plugin>>;
@@ -19,6 +20,130 @@ pub trait PluginFactory: 'static {
 pub enum Action {
     Publish,
     Subscribe,
+    /// Sends a message to a subscriber, it is only checked if `deliver_acl` is enabled.
+    Deliver,
+}
+
+/// An action checked by [`Plugin::check_acl`].
+#[derive(Debug, Copy, Clone)]
+pub struct AclRequest<'a> {
+    pub remote_addr: &'a RemoteAddr,
+    /// `None` for the messages published through the admin API.
+    pub client_id: Option<&'a str>,
+    pub uid: Option<&'a str>,
+    /// `None` for the messages published through the admin API.
+    pub level: Option<ProtocolLevel>,
+    pub action: Action,
+    /// The topic of the message, or the filter of a subscription.
+    pub topic: &'a str,
+    /// The QoS of the message, or the requested QoS of a subscription.
+    pub qos: Qos,
+    /// The retain flag of the message, `false` for subscriptions.
+    pub retain: bool,
+}
+
+/// The result of a single step of an enhanced authentication exchange.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EnhancedAuthResult {
+    /// Send the authentication data to the client and wait for it to respond.
+    Continue(Bytes),
+
+    /// The client is authenticated as `uid`.
+    Success { uid: String, data: Option<Bytes> },
+
+    /// The client failed to authenticate.
+    Failure,
+}
+
+/// Represents an enhanced authentication exchange for a single connection.
+///
+/// It is created by [`Plugin::enhanced_auth`] when the client starts the exchange with a
+/// CONNECT packet, or with an AUTH packet when it re-authenticates.
+#[async_trait::async_trait]
+pub trait EnhancedAuth: Send + Sync + 'static {
+    async fn step(&mut self, data: Option<Bytes>) -> PluginResult<EnhancedAuthResult>;
+}
+
+/// The result of [`Plugin::authorize_subscribe`] for a topic filter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscribeAuthorization {
+    /// `QoS0`, `QoS1` or `QoS2` grants the subscription with at most that QoS, an error code
+    /// refuses it.
+    pub reason_code: SubscribeReasonCode,
+    /// Sent in the SUBACK packet to MQTT 5 clients.
+    pub reason_string: Option<String>,
+}
+
+impl SubscribeAuthorization {
+    pub fn granted(max_qos: Qos) -> Self {
+        Self {
+            reason_code: match max_qos {
+                Qos::AtMostOnce => SubscribeReasonCode::QoS0,
+                Qos::AtLeastOnce => SubscribeReasonCode::QoS1,
+                Qos::ExactlyOnce => SubscribeReasonCode::QoS2,
+            },
+            reason_string: None,
+        }
+    }
+
+    pub fn refused(reason_code: SubscribeReasonCode) -> Self {
+        Self {
+            reason_code,
+            reason_string: None,
+        }
+    }
+
+    pub fn with_reason_string(self, reason_string: impl Into<String>) -> Self {
+        Self {
+            reason_string: Some(reason_string.into()),
+            ..self
+        }
+    }
+}
+
+/// The result of [`Plugin::intercept_publish`].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum PublishInterceptResult {
+    /// Routes the message unchanged.
+    Continue,
+
+    /// Routes the message with the changes, the fields that are `None` are unchanged.
+    ///
+    /// A new topic must be valid and allowed by [`Plugin::check_acl`], otherwise the message is
+    /// rejected with `TopicNameInvalid` or `NotAuthorized`.
+    Modified {
+        topic: Option<ByteString>,
+        payload: Option<Bytes>,
+        properties: Option<PublishProperties>,
+    },
+
+    /// Acknowledges the message as usual but does not route it.
+    Drop,
+
+    /// Does not route the message, and acknowledges it with the reason code in the PUBACK or
+    /// PUBREC packet.
+    ///
+    /// The clients before MQTT 5 have no reason codes, their message is acknowledged as usual.
+    Reject(PubAckReasonCode),
+}
+
+/// The result of [`Plugin::intercept_delivery`].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum DeliveryInterceptResult {
+    /// Sends the message unchanged.
+    Continue,
+
+    /// Sends the message with the changes, the fields that are `None` are unchanged.
+    Modified {
+        topic: Option<ByteString>,
+        payload: Option<Bytes>,
+        properties: Option<PublishProperties>,
+    },
+
+    /// Does not send the message to this subscriber.
+    Drop,
 }
 
 /// Represents a rsmqtt plugin
@@ -29,16 +154,34 @@ pub trait Plugin: Send + Sync + 'static {
         Ok(None)
     }
 
-    async fn check_acl(
+    /// Starts an enhanced authentication exchange.
+    ///
+    /// Returns `None` if the plugin does not support the authentication method.
+    async fn enhanced_auth(
         &self,
         remote_addr: &RemoteAddr,
-        uid: Option<&str>,
-        action: Action,
-        topic: &str,
-    ) -> PluginResult<bool> {
+        client_id: &str,
+        method: &str,
+    ) -> PluginResult<Option<Box<dyn EnhancedAuth>>> {
+        Ok(None)
+    }
+
+    async fn check_acl(&self, request: &AclRequest<'_>) -> PluginResult<bool> {
         Ok(true)
     }
 
+    /// Called for each topic filter of a SUBSCRIBE packet allowed by [`Plugin::check_acl`], with
+    /// the same request.
+    ///
+    /// The subscription is granted with the lowest QoS of the plugins, or refused with the
+    /// reason code of the first plugin that refuses it.
+    async fn authorize_subscribe(
+        &self,
+        request: &AclRequest<'_>,
+    ) -> PluginResult<SubscribeAuthorization> {
+        Ok(SubscribeAuthorization::granted(Qos::ExactlyOnce))
+    }
+
     async fn on_client_connected(
         &self,
         remote_addr: &RemoteAddr,
@@ -62,10 +205,41 @@ pub trait Plugin: Send + Sync + 'static {
 
     async fn on_session_unsubscribed(&self, client_id: &str, uid: Option<&str>, topic: &str) {}
 
-    async fn on_message_publish(
+    /// Called when a message is published, before it is retained and routed to the subscribers.
+    ///
+    /// The plugins are called in order, each one receives the message modified by the previous
+    /// ones. `client_id` is `None` for the messages published through the admin API.
+    async fn intercept_publish(
+        &self,
+        remote_addr: &RemoteAddr,
+        client_id: Option<&str>,
+        uid: Option<&str>,
+        msg: &Message,
+    ) -> PluginResult<PublishInterceptResult> {
+        Ok(PublishInterceptResult::Continue)
+    }
+
+    /// Called before a message is sent to a subscriber, the changes only affect this subscriber.
+    ///
+    /// The plugins are called in order, each one receives the message modified by the previous
+    /// ones.
+    async fn intercept_delivery(
         &self,
+        remote_addr: &RemoteAddr,
         client_id: &str,
         uid: Option<&str>,
+        msg: &Message,
+    ) -> PluginResult<DeliveryInterceptResult> {
+        Ok(DeliveryInterceptResult::Continue)
+    }
+
+    /// Called when a message is accepted, before it is routed to the subscribers.
+    ///
+    /// `client_id` is `None` for the messages published through the admin API.
+    async fn on_message_publish(
+        &self,
+        client_id: Option<&str>,
+        uid: Option<&str>,
         topic: &str,
         qos: Qos,
         retain: bool,
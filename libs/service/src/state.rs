@@ -1,18 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use bytes::Bytes;
 use bytestring::ByteString;
+use codec::{ProtocolLevel, PubAckReasonCode, PublishProperties, Qos};
+use regex::Regex;
 use tokio::sync::{mpsc, watch, Mutex, RwLock};
 use tokio_stream::Stream;
 
-use crate::config::ServiceConfig;
+use crate::client_loop::RemoteAddr;
+use crate::config::{QueueLimits, ServiceConfig};
+use crate::filter_util;
+use crate::message::Message;
 use crate::metrics::{Metrics, MetricsCalc};
-use crate::plugin::Plugin;
+use crate::plugin::{
+    AclRequest, Action, DeliveryInterceptResult, Plugin, PublishInterceptResult,
+    SubscribeAuthorization,
+};
 use crate::rewrite::Rewrite;
-use crate::storage::Storage;
+use crate::storage::{self, SessionInfo, StorageBackend, SubscriptionInfo};
 
 #[derive(Debug, Default)]
 pub struct ServiceMetrics {
@@ -27,9 +36,48 @@ pub struct ServiceMetrics {
     pub msgs_dropped: AtomicUsize,
     pub socket_connections: AtomicUsize,
     pub connection_count: AtomicUsize,
+    listeners: parking_lot::Mutex<BTreeMap<(String, String), Arc<ListenerMetrics>>>,
+}
+
+/// Counters of the connections accepted by a listener with a protocol.
+#[derive(Debug, Default)]
+pub struct ListenerMetrics {
+    pub bytes_received: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+    pub pub_bytes_received: AtomicUsize,
+    pub pub_bytes_sent: AtomicUsize,
+    pub msgs_received: AtomicUsize,
+    pub msgs_sent: AtomicUsize,
+    pub pub_msgs_received: AtomicUsize,
+    pub pub_msgs_sent: AtomicUsize,
+    pub socket_connections: AtomicUsize,
+    pub connection_count: AtomicUsize,
 }
 
 impl ServiceMetrics {
+    /// Returns the counters of the listener and protocol of `remote_addr`.
+    pub fn listener(&self, remote_addr: &RemoteAddr) -> Arc<ListenerMetrics> {
+        self.listeners
+            .lock()
+            .entry((
+                remote_addr.listener.to_string(),
+                remote_addr.protocol.to_string(),
+            ))
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the counters of all the listeners as `(listener, protocol, metrics)`.
+    pub fn listeners(&self) -> Vec<(String, String, Arc<ListenerMetrics>)> {
+        self.listeners
+            .lock()
+            .iter()
+            .map(|((listener, protocol), metrics)| {
+                (listener.clone(), protocol.clone(), metrics.clone())
+            })
+            .collect()
+    }
+
     #[inline]
     pub fn inc_bytes_received(&self, value: usize) {
         self.bytes_received.fetch_add(value, Ordering::SeqCst);
@@ -96,18 +144,208 @@ impl ServiceMetrics {
     }
 }
 
+/// Updates the service counters together with the counters of the listener of a connection.
+pub(crate) struct ConnectionMetrics {
+    service: Arc<ServiceMetrics>,
+    listener: Arc<ListenerMetrics>,
+}
+
+impl ConnectionMetrics {
+    pub fn new(service: Arc<ServiceMetrics>, remote_addr: &RemoteAddr) -> Self {
+        let listener = service.listener(remote_addr);
+        Self { service, listener }
+    }
+
+    #[inline]
+    pub fn inc_bytes_received(&self, value: usize) {
+        self.service.inc_bytes_received(value);
+        self.listener
+            .bytes_received
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_bytes_sent(&self, value: usize) {
+        self.service.inc_bytes_sent(value);
+        self.listener.bytes_sent.fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_pub_bytes_received(&self, value: usize) {
+        self.service.inc_pub_bytes_received(value);
+        self.listener
+            .pub_bytes_received
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_pub_bytes_sent(&self, value: usize) {
+        self.service.inc_pub_bytes_sent(value);
+        self.listener
+            .pub_bytes_sent
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_msgs_received(&self, value: usize) {
+        self.service.inc_msgs_received(value);
+        self.listener
+            .msgs_received
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_msgs_sent(&self, value: usize) {
+        self.service.inc_msgs_sent(value);
+        self.listener.msgs_sent.fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_pub_msgs_received(&self, value: usize) {
+        self.service.inc_pub_msgs_received(value);
+        self.listener
+            .pub_msgs_received
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_pub_msgs_sent(&self, value: usize) {
+        self.service.inc_pub_msgs_sent(value);
+        self.listener
+            .pub_msgs_sent
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_socket_connections(&self, value: usize) {
+        self.service.inc_socket_connections(value);
+        self.listener
+            .socket_connections
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn dec_socket_connections(&self, value: usize) {
+        self.service.dec_socket_connections(value);
+        self.listener
+            .socket_connections
+            .fetch_sub(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn inc_connection_count(&self, value: usize) {
+        self.service.inc_connection_count(value);
+        self.listener
+            .connection_count
+            .fetch_add(value, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn dec_connection_count(&self, value: usize) {
+        self.service.dec_connection_count(value);
+        self.listener
+            .connection_count
+            .fetch_sub(value, Ordering::SeqCst);
+    }
+}
+
 #[derive(Debug)]
 pub enum Control {
     SessionTakenOver,
+    Kick { remove_session: bool },
+    ServerShuttingDown,
+}
+
+/// The publisher of a message passed to [`ServiceState::accept_publish`].
+pub(crate) struct Publisher<'a> {
+    pub(crate) remote_addr: &'a RemoteAddr,
+    /// `None` for the messages published through the admin API.
+    pub(crate) client_id: Option<&'a str>,
+    pub(crate) uid: Option<&'a str>,
+    /// `None` for the messages published through the admin API.
+    pub(crate) level: Option<ProtocolLevel>,
+    /// The maximum QoS announced to the publisher, a reload doesn't change it for the existing
+    /// connections.
+    pub(crate) maximum_qos: Qos,
+}
+
+impl<'a> Publisher<'a> {
+    fn acl_request(&self, topic: &'a str, qos: Qos, retain: bool) -> AclRequest<'a> {
+        AclRequest {
+            remote_addr: self.remote_addr,
+            client_id: self.client_id,
+            uid: self.uid,
+            level: self.level,
+            action: Action::Publish,
+            topic,
+            qos,
+            retain,
+        }
+    }
+}
+
+/// The result of publishing a message.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum PublishOutcome {
+    /// The message is retained and routed to the subscribers.
+    Accepted(Message),
+
+    /// The QoS of the message is greater than the `maximum_qos` of the server.
+    QosNotSupported,
+
+    /// The publisher is not allowed to publish to the topic.
+    NotAuthorized,
+
+    /// The message is dropped or rejected, it is acknowledged with the reason code.
+    Unrouted(PubAckReasonCode),
+}
+
+/// The settings that can be replaced with [`ServiceState::reload`].
+struct Settings {
+    config: Arc<ServiceConfig>,
+    plugins: Arc<[(&'static str, Arc<dyn Plugin>)]>,
+    rewrites: Vec<Rewrite>,
+    client_queue_limits: Vec<(Regex, QueueLimits)>,
+}
+
+impl Settings {
+    fn try_new(
+        config: ServiceConfig,
+        plugins: Vec<(&'static str, Arc<dyn Plugin>)>,
+    ) -> Result<Self> {
+        let mut rewrites = Vec::new();
+
+        for rewrite_cfg in &config.rewrites {
+            rewrites
+                .push(Rewrite::try_new(rewrite_cfg).with_context(|| {
+                    format!("invalid rewrite pattern: {}", rewrite_cfg.pattern)
+                })?);
+        }
+
+        let mut client_queue_limits = Vec::new();
+
+        for limits_cfg in &config.client_queue_limits {
+            let re = Regex::new(&limits_cfg.client_id)
+                .with_context(|| format!("invalid client id pattern: {}", limits_cfg.client_id))?;
+            client_queue_limits.push((re, limits_cfg.limits));
+        }
+
+        Ok(Self {
+            config: Arc::new(config),
+            plugins: plugins.into(),
+            rewrites,
+            client_queue_limits,
+        })
+    }
 }
 
 pub struct ServiceState {
-    pub config: ServiceConfig,
+    settings: parking_lot::RwLock<Arc<Settings>>,
+    generation: AtomicUsize,
     pub(crate) connections: RwLock<HashMap<String, mpsc::UnboundedSender<Control>>>,
-    pub(crate) storage: Storage,
+    pub(crate) storage: Box<dyn StorageBackend>,
     pub(crate) service_metrics: Arc<ServiceMetrics>,
-    pub(crate) plugins: Vec<(&'static str, Arc<dyn Plugin>)>,
-    rewrites: Vec<Rewrite>,
     metrics_calc: Mutex<MetricsCalc>,
     metrics_sender: watch::Sender<Metrics>,
     metrics_receiver: watch::Receiver<Metrics>,
@@ -119,23 +357,23 @@ impl ServiceState {
         plugins: Vec<(&'static str, Arc<dyn Plugin>)>,
     ) -> Result<Arc<Self>> {
         let (stat_sender, stat_receiver) = watch::channel(Metrics::default());
-        let mut rewrites = Vec::new();
+        let settings = Settings::try_new(config, plugins)?;
 
-        for rewrite_cfg in &config.rewrites {
-            rewrites
-                .push(Rewrite::try_new(rewrite_cfg).with_context(|| {
-                    format!("invalid rewrite pattern: {}", rewrite_cfg.pattern)
-                })?);
-        }
+        let service_metrics = Arc::new(ServiceMetrics::default());
+        let storage = storage::open(
+            &settings.config.storage,
+            settings.config.shared_subscriptions.clone(),
+            service_metrics.clone(),
+        )
+        .context("failed to open storage")?;
 
         let state = Arc::new(Self {
-            config,
+            settings: parking_lot::RwLock::new(Arc::new(settings)),
+            generation: AtomicUsize::new(0),
             connections: RwLock::new(HashMap::new()),
-            storage: Storage::default(),
-            service_metrics: Arc::new(ServiceMetrics::default()),
+            storage,
+            service_metrics,
             metrics_sender: stat_sender,
-            plugins,
-            rewrites,
             metrics_receiver: stat_receiver,
             metrics_calc: Mutex::new(MetricsCalc::new()),
         });
@@ -153,8 +391,38 @@ impl ServiceState {
         Ok(state)
     }
 
+    /// Replaces the config, the rewrites and the plugins.
+    ///
+    /// The connected clients keep the limits negotiated with the old config, and the storage and
+    /// shared subscription settings are only read at startup.
+    pub fn reload(
+        &self,
+        config: ServiceConfig,
+        plugins: Vec<(&'static str, Arc<dyn Plugin>)>,
+    ) -> Result<()> {
+        let settings = Settings::try_new(config, plugins)?;
+        *self.settings.write() = Arc::new(settings);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[inline]
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().clone()
+    }
+
+    #[inline]
+    pub fn config(&self) -> Arc<ServiceConfig> {
+        self.settings().config.clone()
+    }
+
+    #[inline]
+    pub(crate) fn plugins(&self) -> Arc<[(&'static str, Arc<dyn Plugin>)]> {
+        self.settings().plugins.clone()
+    }
+
     pub(crate) fn rewrite(&self, topic: &mut ByteString) {
-        for rewrite in &self.rewrites {
+        for rewrite in &self.settings().rewrites {
             if let Some(new_topic) = rewrite.rewrite(topic) {
                 *topic = new_topic.into();
                 break;
@@ -162,6 +430,18 @@ impl ServiceState {
         }
     }
 
+    /// Returns the queue limits of the session, the first matching client id pattern takes
+    /// precedence over the global limits.
+    pub(crate) fn queue_limits(&self, client_id: &str) -> QueueLimits {
+        let settings = self.settings();
+        settings
+            .client_queue_limits
+            .iter()
+            .find(|(re, _)| re.is_match(client_id))
+            .map(|(_, limits)| *limits)
+            .unwrap_or(settings.config.queue_limits)
+    }
+
     pub async fn update_metrics(&self) {
         let metrics = self
             .metrics_calc
@@ -175,7 +455,529 @@ impl ServiceState {
         *self.metrics_receiver.borrow()
     }
 
+    pub fn listener_metrics(&self) -> Vec<(String, String, Arc<ListenerMetrics>)> {
+        self.service_metrics.listeners()
+    }
+
     pub fn metrics_stream(&self) -> impl Stream<Item = Metrics> + Send + 'static {
         tokio_stream::wrappers::WatchStream::new(self.metrics_receiver.clone())
     }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.storage.sessions()
+    }
+
+    pub fn session(&self, client_id: &str) -> Option<SessionInfo> {
+        self.storage.session(client_id)
+    }
+
+    /// Removes a session, a connected client is disconnected first.
+    ///
+    /// Returns `false` if the session does not exist.
+    pub async fn remove_session(&self, client_id: &str) -> bool {
+        // holds the lock so that the client cannot connect while the session is being removed
+        let connections = self.connections.read().await;
+        match connections.get(client_id) {
+            Some(control_sender) => control_sender
+                .send(Control::Kick {
+                    remove_session: true,
+                })
+                .is_ok(),
+            None => self.storage.remove_session(client_id),
+        }
+    }
+
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.storage.subscriptions()
+    }
+
+    pub fn client_subscriptions(&self, client_id: &str) -> Vec<SubscriptionInfo> {
+        self.storage.client_subscriptions(client_id)
+    }
+
+    pub fn retained_messages(&self) -> Vec<Message> {
+        self.storage.retained_messages()
+    }
+
+    pub fn retained_message(&self, topic: &str) -> Option<Message> {
+        self.storage.retained_message(topic)
+    }
+
+    /// Returns `false` if there is no retained message for the topic.
+    pub fn remove_retained_message(&self, topic: &str) -> bool {
+        if self.storage.retained_message(topic).is_none() {
+            return false;
+        }
+        self.storage.update_retained_message(Message::new(
+            topic.to_string(),
+            Qos::AtMostOnce,
+            Bytes::new(),
+        ));
+        true
+    }
+
+    /// Publishes a message on behalf of the server.
+    pub fn publish(&self, mut msg: Message) {
+        let mut topic = msg.topic().clone();
+        self.rewrite(&mut topic);
+        if topic != *msg.topic() {
+            msg = msg.with_topic(topic);
+        }
+
+        if msg.is_retain() {
+            self.storage.update_retained_message(msg.clone());
+        }
+        self.storage.deliver(vec![msg]);
+    }
+
+    /// Publishes a message on behalf of `uid` through the same path as the messages published by
+    /// the clients.
+    pub async fn publish_as(
+        &self,
+        remote_addr: &RemoteAddr,
+        uid: Option<&str>,
+        msg: Message,
+    ) -> Result<PublishOutcome> {
+        let metrics = ConnectionMetrics::new(self.service_metrics.clone(), remote_addr);
+        metrics.inc_pub_bytes_received(msg.payload().len());
+        metrics.inc_pub_msgs_received(1);
+
+        let publisher = Publisher {
+            remote_addr,
+            client_id: None,
+            uid,
+            level: None,
+            maximum_qos: self.config().maximum_qos,
+        };
+        let outcome = self.accept_publish(&publisher, msg).await?;
+        if let PublishOutcome::Accepted(msg) = &outcome {
+            self.storage.deliver(vec![msg.clone()]);
+        }
+        Ok(outcome)
+    }
+
+    /// Checks the QoS and the ACL of a published message, then rewrites, intercepts and retains
+    /// it, and calls the `on_message_publish` hooks.
+    ///
+    /// The topic of `msg` must be valid. An accepted message is not delivered, the caller delivers
+    /// it when the publish flow allows it.
+    pub(crate) async fn accept_publish(
+        &self,
+        publisher: &Publisher<'_>,
+        mut msg: Message,
+    ) -> Result<PublishOutcome> {
+        if msg.qos() > publisher.maximum_qos {
+            return Ok(PublishOutcome::QosNotSupported);
+        }
+
+        let qos = msg.qos();
+        let retain = msg.is_retain();
+        if !self
+            .check_acl(&publisher.acl_request(msg.topic(), qos, retain))
+            .await?
+        {
+            return Ok(PublishOutcome::NotAuthorized);
+        }
+
+        let mut topic = msg.topic().clone();
+        self.rewrite(&mut topic);
+        if topic != *msg.topic() {
+            msg = msg.with_topic(topic.clone());
+        }
+        if let Some(client_id) = publisher.client_id {
+            msg = msg.with_from_client_id(client_id);
+        }
+        if let Some(uid) = publisher.uid {
+            msg = msg.with_from_uid(uid);
+        }
+
+        match self
+            .intercept_publish(
+                publisher.remote_addr,
+                publisher.client_id,
+                publisher.uid,
+                &mut msg,
+            )
+            .await?
+        {
+            PublishInterceptResult::Continue | PublishInterceptResult::Modified { .. } => {}
+            PublishInterceptResult::Drop => {
+                return Ok(PublishOutcome::Unrouted(PubAckReasonCode::Success));
+            }
+            PublishInterceptResult::Reject(reason_code) => {
+                return Ok(PublishOutcome::Unrouted(reason_code));
+            }
+        }
+
+        // the publisher must also be allowed to publish to the topic chosen by the plugins
+        if *msg.topic() != topic
+            && !self
+                .check_acl(&publisher.acl_request(msg.topic(), qos, retain))
+                .await?
+        {
+            return Ok(PublishOutcome::Unrouted(PubAckReasonCode::NotAuthorized));
+        }
+
+        if retain {
+            self.storage.update_retained_message(msg.clone());
+        }
+
+        for (_, plugin) in self.plugins().iter() {
+            plugin
+                .on_message_publish(
+                    publisher.client_id,
+                    publisher.uid,
+                    msg.topic(),
+                    msg.qos(),
+                    msg.is_retain(),
+                    msg.payload().clone(),
+                )
+                .await;
+        }
+
+        Ok(PublishOutcome::Accepted(msg))
+    }
+
+    /// Returns the uid of the first plugin that accepts the credentials.
+    pub async fn auth(&self, username: &str, password: &str) -> Result<Option<String>> {
+        for (name, plugin) in self.plugins().iter() {
+            match plugin.auth(username, password).await {
+                Ok(Some(uid)) => return Ok(Some(uid)),
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::auth",
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `false` if any plugin denies the action.
+    pub async fn check_acl(&self, request: &AclRequest<'_>) -> Result<bool> {
+        for (name, plugin) in self.plugins().iter() {
+            match plugin.check_acl(request).await {
+                Ok(false) => return Ok(false),
+                Ok(true) => {}
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::check_acl",
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Combines the authorizations of the plugins for a topic filter, the first reason string is
+    /// kept.
+    pub async fn authorize_subscribe(
+        &self,
+        request: &AclRequest<'_>,
+    ) -> Result<SubscribeAuthorization> {
+        let mut res = SubscribeAuthorization::granted(Qos::ExactlyOnce);
+        for (name, plugin) in self.plugins().iter() {
+            match plugin.authorize_subscribe(request).await {
+                Ok(authorization) => {
+                    let reason_string = res.reason_string.or(authorization.reason_string);
+                    match (res.reason_code.qos(), authorization.reason_code.qos()) {
+                        (Some(max_qos), Some(plugin_max_qos)) => {
+                            res = SubscribeAuthorization::granted(max_qos.min(plugin_max_qos));
+                            res.reason_string = reason_string;
+                        }
+                        _ => {
+                            return Ok(SubscribeAuthorization {
+                                reason_code: authorization.reason_code,
+                                reason_string,
+                            })
+                        }
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::authorize_subscribe",
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Applies the modifications of the plugins to `msg`, returns the result of the plugin that
+    /// drops or rejects the message, or `Continue` if none of them does.
+    ///
+    /// A message modified to an invalid topic is rejected with `TopicNameInvalid`.
+    pub async fn intercept_publish(
+        &self,
+        remote_addr: &RemoteAddr,
+        client_id: Option<&str>,
+        uid: Option<&str>,
+        msg: &mut Message,
+    ) -> Result<PublishInterceptResult> {
+        for (name, plugin) in self.plugins().iter() {
+            match plugin
+                .intercept_publish(remote_addr, client_id, uid, msg)
+                .await
+            {
+                Ok(PublishInterceptResult::Continue) => {}
+                Ok(PublishInterceptResult::Modified {
+                    topic: Some(topic), ..
+                }) if !valid_plugin_topic(&topic) => {
+                    tracing::warn!(
+                        plugin = %name,
+                        topic = %topic,
+                        "plugin::intercept_publish returned an invalid topic",
+                    );
+                    return Ok(PublishInterceptResult::Reject(
+                        PubAckReasonCode::TopicNameInvalid,
+                    ));
+                }
+                Ok(PublishInterceptResult::Modified {
+                    topic,
+                    payload,
+                    properties,
+                }) => modify_message(name, msg, topic, payload, properties)?,
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::intercept_publish",
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(PublishInterceptResult::Continue)
+    }
+
+    /// Applies the modifications of the plugins to `msg` before it is sent to a subscriber,
+    /// returns `false` if a plugin drops it.
+    ///
+    /// A message modified to an invalid topic is dropped.
+    pub async fn intercept_delivery(
+        &self,
+        remote_addr: &RemoteAddr,
+        client_id: &str,
+        uid: Option<&str>,
+        msg: &mut Message,
+    ) -> Result<bool> {
+        for (name, plugin) in self.plugins().iter() {
+            match plugin
+                .intercept_delivery(remote_addr, client_id, uid, msg)
+                .await
+            {
+                Ok(DeliveryInterceptResult::Continue) => {}
+                Ok(DeliveryInterceptResult::Modified {
+                    topic: Some(topic), ..
+                }) if !valid_plugin_topic(&topic) => {
+                    tracing::warn!(
+                        plugin = %name,
+                        topic = %topic,
+                        "plugin::intercept_delivery returned an invalid topic",
+                    );
+                    return Ok(false);
+                }
+                Ok(DeliveryInterceptResult::Modified {
+                    topic,
+                    payload,
+                    properties,
+                }) => modify_message(name, msg, topic, payload, properties)?,
+                Ok(DeliveryInterceptResult::Drop) => return Ok(false),
+                Err(err) => {
+                    tracing::error!(
+                        plugin = %name,
+                        error = %err,
+                        "failed to call plugin::intercept_delivery",
+                    );
+                    return Err(err);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of times the settings have been reloaded.
+    #[inline]
+    pub(crate) fn generation(&self) -> usize {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Disconnects all the clients with the `ServerShuttingDown` reason code, waits up to
+    /// `drain_period` for the connections to be closed, and then persists the storage.
+    pub async fn shutdown(&self, drain_period: Duration) -> Result<()> {
+        for control_sender in self.connections.read().await.values() {
+            control_sender.send(Control::ServerShuttingDown).ok();
+        }
+
+        let deadline = Instant::now() + drain_period;
+        while !self.connections.read().await.is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        self.storage.flush().context("failed to persist storage")
+    }
+
+    /// Disconnects a client with the `AdministrativeAction` reason code.
+    ///
+    /// Returns `false` if the client is not connected.
+    pub async fn kick(&self, client_id: &str) -> bool {
+        match self.connections.read().await.get(client_id) {
+            Some(control_sender) => control_sender
+                .send(Control::Kick {
+                    remove_session: false,
+                })
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Returns `true` if a plugin can route a message to `topic`.
+#[inline]
+fn valid_plugin_topic(topic: &str) -> bool {
+    !topic.starts_with('$') && filter_util::valid_topic(topic)
+}
+
+/// Applies the changes returned by a plugin to a message.
+fn modify_message(
+    plugin: &str,
+    msg: &mut Message,
+    topic: Option<ByteString>,
+    payload: Option<Bytes>,
+    properties: Option<PublishProperties>,
+) -> Result<()> {
+    let mut modified = msg.clone();
+    if let Some(topic) = topic {
+        anyhow::ensure!(
+            valid_plugin_topic(&topic),
+            "plugin '{}' returned an invalid topic: {}",
+            plugin,
+            topic
+        );
+        modified = modified.with_topic(topic);
+    }
+    if let Some(payload) = payload {
+        modified = modified.with_payload(payload);
+    }
+    if let Some(properties) = properties {
+        modified = modified.with_properties(properties);
+    }
+    *msg = modified;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordPlugin {
+        published: parking_lot::Mutex<Vec<(Option<String>, String)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for RecordPlugin {
+        async fn intercept_publish(
+            &self,
+            _remote_addr: &RemoteAddr,
+            _client_id: Option<&str>,
+            _uid: Option<&str>,
+            msg: &Message,
+        ) -> Result<PublishInterceptResult> {
+            Ok(match msg.topic().as_ref() {
+                "drop" => PublishInterceptResult::Drop,
+                "modify" => PublishInterceptResult::Modified {
+                    topic: Some("modified".into()),
+                    payload: None,
+                    properties: None,
+                },
+                _ => PublishInterceptResult::Continue,
+            })
+        }
+
+        async fn on_message_publish(
+            &self,
+            _client_id: Option<&str>,
+            uid: Option<&str>,
+            topic: &str,
+            _qos: Qos,
+            _retain: bool,
+            _payload: Bytes,
+        ) {
+            self.published
+                .lock()
+                .push((uid.map(ToString::to_string), topic.to_string()));
+        }
+    }
+
+    fn remote_addr() -> RemoteAddr {
+        RemoteAddr {
+            listener: "http".into(),
+            protocol: "http".into(),
+            addr: None,
+            mountpoint: None,
+            cert_identity: None,
+            peer_cred: None,
+            uid: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_as() {
+        let plugin = Arc::new(RecordPlugin::default());
+        let state = ServiceState::new(
+            ServiceConfig {
+                maximum_qos: Qos::AtLeastOnce,
+                ..ServiceConfig::default()
+            },
+            vec![("record", plugin.clone())],
+        )
+        .unwrap();
+        let publish = |topic: &'static str, qos| {
+            let state = state.clone();
+            async move {
+                state
+                    .publish_as(
+                        &remote_addr(),
+                        Some("admin"),
+                        Message::new(topic, qos, "1").with_retain(true),
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert!(matches!(
+            publish("test", Qos::ExactlyOnce).await,
+            PublishOutcome::QosNotSupported
+        ));
+        assert!(matches!(
+            publish("drop", Qos::AtLeastOnce).await,
+            PublishOutcome::Unrouted(PubAckReasonCode::Success)
+        ));
+        assert!(matches!(
+            publish("modify", Qos::AtLeastOnce).await,
+            PublishOutcome::Accepted(msg) if msg.topic() == "modified"
+        ));
+
+        assert!(state.retained_message("drop").is_none());
+        assert!(state.retained_message("modify").is_none());
+        let retained = state.retained_message("modified").unwrap();
+        assert_eq!(retained.from_uid().map(|uid| uid.as_ref()), Some("admin"));
+        assert_eq!(
+            *plugin.published.lock(),
+            vec![(Some("admin".to_string()), "modified".to_string())]
+        );
+    }
 }
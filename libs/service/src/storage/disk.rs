@@ -0,0 +1,712 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::num::{NonZeroU16, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+use anyhow::{Context, Result};
+use codec::{LastWill, Publish, Qos, RetainHandling};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use super::memory::{Journal, Record};
+use super::{
+    MemoryStorage, Qos2State, SessionInfo, StorageBackend, StorageMetrics, SubscriptionInfo,
+};
+use crate::config::{QueueLimits, SharedSubscriptionConfig};
+use crate::filter_util::Filter;
+use crate::message::Message;
+use crate::state::ServiceMetrics;
+
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const LOG_FILE: &str = "log";
+
+/// A write to the storage files, executed in order by the writer thread.
+enum Command {
+    /// Appends an encoded record to the log.
+    Append(Vec<u8>),
+    /// Replaces the snapshot and truncates the log, the result is sent to `done` if it is present.
+    Snapshot {
+        data: Vec<u8>,
+        done: Option<mpsc::Sender<Result<()>>>,
+    },
+    Stop,
+}
+
+/// Append-only log of the changes made since the last snapshot.
+///
+/// Each record is stored as a little-endian `u32` length followed by the bincode encoded record.
+/// The records are encoded by the caller and written by the writer thread.
+struct Log {
+    sender: Mutex<mpsc::Sender<Command>>,
+    records: AtomicUsize,
+}
+
+impl Log {
+    fn send(&self, command: Command) {
+        if self.sender.lock().send(command).is_err() {
+            tracing::error!("storage writer thread is stopped");
+        }
+    }
+}
+
+impl Journal for Log {
+    fn append(&self, record: Record) {
+        let data = match bincode::serialize(&record) {
+            Ok(data) => data,
+            Err(err) => {
+                tracing::error!(error = %err, "failed to encode storage log record");
+                return;
+            }
+        };
+
+        let mut buf = Vec::with_capacity(4 + data.len());
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&data);
+        self.send(Command::Append(buf));
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Executes the commands until [`Command::Stop`] is received, so that the runtime threads never
+/// wait for the disk.
+fn run_writer(path: PathBuf, mut log_file: File, receiver: mpsc::Receiver<Command>) {
+    for command in receiver {
+        match command {
+            Command::Append(buf) => {
+                if let Err(err) = log_file.write_all(&buf) {
+                    tracing::error!(error = %err, "failed to write storage log");
+                }
+            }
+            Command::Snapshot { data, done } => {
+                let res = write_snapshot(&path, &log_file, &data);
+                if let Err(err) = &res {
+                    tracing::error!(error = %err, "failed to save storage snapshot");
+                }
+                if let Some(done) = done {
+                    done.send(res).ok();
+                }
+            }
+            Command::Stop => break,
+        }
+    }
+}
+
+fn write_snapshot(path: &Path, log_file: &File, data: &[u8]) -> Result<()> {
+    let tmp_path = path.join(SNAPSHOT_TMP_FILE);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path.join(SNAPSHOT_FILE))?;
+
+    log_file.set_len(0)?;
+    log_file.sync_all()?;
+    Ok(())
+}
+
+/// Replays the log and returns the length of the valid part, a partially written record at the
+/// end is ignored.
+fn replay_log(memory: &MemoryStorage, file: &mut File) -> Result<u64> {
+    let mut reader = BufReader::new(file);
+    let mut offset = 0;
+
+    loop {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        let len = u32::from_le_bytes(len) as usize;
+        let mut data = vec![0; len];
+        match reader.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        match bincode::deserialize(&data) {
+            Ok(record) => memory.apply(record),
+            Err(err) => {
+                tracing::warn!(error = %err, "invalid storage log record");
+                break;
+            }
+        }
+        offset += 4 + len as u64;
+    }
+
+    Ok(offset)
+}
+
+/// On-disk storage, keeps the state in memory and persists every change to an append-only log
+/// which is compacted into a snapshot every `snapshot_interval` records.
+///
+/// The files are written by a dedicated thread, the state is only locked while it is encoded.
+pub struct DiskStorage {
+    memory: MemoryStorage,
+    log: Arc<Log>,
+    snapshot_interval: usize,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl DiskStorage {
+    pub fn open(
+        path: impl AsRef<Path>,
+        snapshot_interval: usize,
+        shared_subscriptions: SharedSubscriptionConfig,
+        service_metrics: Arc<ServiceMetrics>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)
+            .with_context(|| format!("failed to create storage directory: {}", path.display()))?;
+
+        let mut memory = match File::open(path.join(SNAPSHOT_FILE)) {
+            Ok(file) => MemoryStorage::load_snapshot(
+                BufReader::new(file),
+                shared_subscriptions,
+                service_metrics,
+            )
+            .context("failed to load storage snapshot")?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                MemoryStorage::new(shared_subscriptions, service_metrics)
+            }
+            Err(err) => return Err(err).context("failed to open storage snapshot"),
+        };
+
+        let mut log_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path.join(LOG_FILE))
+            .context("failed to open storage log")?;
+        let len = replay_log(&memory, &mut log_file).context("failed to replay storage log")?;
+        log_file.set_len(len)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn({
+                let path = path.to_path_buf();
+                move || run_writer(path, log_file, receiver)
+            })
+            .context("failed to start storage writer thread")?;
+
+        let log = Arc::new(Log {
+            sender: Mutex::new(sender),
+            records: AtomicUsize::new(0),
+        });
+        memory.set_journal(log.clone());
+
+        // clients connected before the restart are now disconnected
+        memory.disconnect_all_sessions();
+
+        let storage = Self {
+            memory,
+            log,
+            snapshot_interval,
+            writer: Some(writer),
+        };
+        storage.save_snapshot(true)?;
+        Ok(storage)
+    }
+
+    /// Queues a snapshot of the current state, and waits for it to be written if `wait` is
+    /// `true`.
+    fn save_snapshot(&self, wait: bool) -> Result<()> {
+        let (done_sender, done_receiver) = mpsc::channel();
+        let done = if wait { Some(done_sender) } else { None };
+        self.memory.save_snapshot(|data| {
+            // the records appended before are in the snapshot, the ones appended after stay in the
+            // log because the state is locked until the command is queued
+            self.log.send(Command::Snapshot { data, done });
+            self.log.records.store(0, Ordering::Relaxed);
+        })?;
+
+        if wait {
+            done_receiver
+                .recv()
+                .context("storage writer thread is stopped")??;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskStorage {
+    fn drop(&mut self) {
+        self.log.send(Command::Stop);
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+impl StorageBackend for DiskStorage {
+    #[inline]
+    fn update_retained_message(&self, msg: Message) {
+        self.memory.update_retained_message(msg)
+    }
+
+    #[inline]
+    fn create_session(
+        &self,
+        client_id: &str,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    ) -> (bool, Arc<Notify>) {
+        self.memory.create_session(
+            client_id,
+            clean_start,
+            session_expiry_interval,
+            queue_limits,
+            last_will,
+        )
+    }
+
+    #[inline]
+    fn disconnect_session(&self, client_id: &str, session_expiry_interval: u32) {
+        self.memory
+            .disconnect_session(client_id, session_expiry_interval)
+    }
+
+    fn update_sessions(&self) {
+        self.memory.update_sessions();
+
+        if self.log.records.load(Ordering::Relaxed) >= self.snapshot_interval {
+            if let Err(err) = self.save_snapshot(false) {
+                tracing::error!(error = %err, "failed to save storage snapshot");
+            }
+        }
+    }
+
+    #[inline]
+    fn subscribe(
+        &self,
+        client_id: &str,
+        filter: Filter<'_>,
+        qos: Qos,
+        no_local: bool,
+        retain_as_published: bool,
+        retain_handling: RetainHandling,
+        id: Option<NonZeroUsize>,
+    ) {
+        self.memory.subscribe(
+            client_id,
+            filter,
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+            id,
+        )
+    }
+
+    #[inline]
+    fn unsubscribe(&self, client_id: &str, filter: Filter<'_>) -> bool {
+        self.memory.unsubscribe(client_id, filter)
+    }
+
+    #[inline]
+    fn next_messages(&self, client_id: &str, limit: Option<usize>) -> Vec<Message> {
+        self.memory.next_messages(client_id, limit)
+    }
+
+    #[inline]
+    fn deliver(&self, msgs: Vec<Message>) {
+        self.memory.deliver(msgs)
+    }
+
+    #[inline]
+    fn redispatch_refused_message(&self, client_id: &str, msg: Message) -> bool {
+        self.memory.redispatch_refused_message(client_id, msg)
+    }
+
+    #[inline]
+    fn add_inflight_pub_packet(
+        &self,
+        client_id: &str,
+        publish: Publish,
+        shared_msg: Option<Message>,
+    ) {
+        self.memory
+            .add_inflight_pub_packet(client_id, publish, shared_msg)
+    }
+
+    #[inline]
+    fn get_inflight_pub_packets(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        remove: bool,
+    ) -> Option<Publish> {
+        self.memory
+            .get_inflight_pub_packets(client_id, packet_id, remove)
+    }
+
+    #[inline]
+    fn get_all_inflight_pub_packets(&self, client_id: &str) -> Vec<Publish> {
+        self.memory.get_all_inflight_pub_packets(client_id)
+    }
+
+    #[inline]
+    fn take_queue_overflowed(&self, client_id: &str) -> bool {
+        self.memory.take_queue_overflowed(client_id)
+    }
+
+    #[inline]
+    fn get_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State> {
+        self.memory.get_qos2_state(client_id, packet_id)
+    }
+
+    #[inline]
+    fn set_qos2_recorded(&self, client_id: &str, packet_id: NonZeroU16) -> bool {
+        self.memory.set_qos2_recorded(client_id, packet_id)
+    }
+
+    #[inline]
+    fn remove_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State> {
+        self.memory.remove_qos2_state(client_id, packet_id)
+    }
+
+    #[inline]
+    fn add_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        msg: Option<Message>,
+    ) -> bool {
+        self.memory
+            .add_uncompleted_message(client_id, packet_id, msg)
+    }
+
+    #[inline]
+    fn has_uncompleted_message(&self, client_id: &str, packet_id: NonZeroU16) -> bool {
+        self.memory.has_uncompleted_message(client_id, packet_id)
+    }
+
+    #[inline]
+    fn take_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+    ) -> Option<Option<Message>> {
+        self.memory.take_uncompleted_message(client_id, packet_id)
+    }
+
+    #[inline]
+    fn flush(&self) -> Result<()> {
+        self.save_snapshot(true)
+    }
+
+    #[inline]
+    fn metrics(&self) -> StorageMetrics {
+        self.memory.metrics()
+    }
+
+    #[inline]
+    fn sessions(&self) -> Vec<SessionInfo> {
+        self.memory.sessions()
+    }
+
+    #[inline]
+    fn session(&self, client_id: &str) -> Option<SessionInfo> {
+        self.memory.session(client_id)
+    }
+
+    #[inline]
+    fn remove_session(&self, client_id: &str) -> bool {
+        self.memory.remove_session(client_id)
+    }
+
+    #[inline]
+    fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.memory.subscriptions()
+    }
+
+    #[inline]
+    fn client_subscriptions(&self, client_id: &str) -> Vec<SubscriptionInfo> {
+        self.memory.client_subscriptions(client_id)
+    }
+
+    #[inline]
+    fn retained_messages(&self) -> Vec<Message> {
+        self.memory.retained_messages()
+    }
+
+    #[inline]
+    fn retained_message(&self, topic: &str) -> Option<Message> {
+        self.memory.retained_message(topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OverflowPolicy;
+    use crate::filter_util::parse_filter;
+    use std::convert::TryInto;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("rsmqtt-storage-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn open(dir: &TempDir) -> DiskStorage {
+        open_with_interval(dir, 10000)
+    }
+
+    fn open_with_interval(dir: &TempDir, snapshot_interval: usize) -> DiskStorage {
+        DiskStorage::open(
+            &dir.0,
+            snapshot_interval,
+            SharedSubscriptionConfig::default(),
+            Arc::default(),
+        )
+        .unwrap()
+    }
+
+    fn retain(storage: &DiskStorage, topic: &str) {
+        storage.update_retained_message(
+            Message::new(topic.to_string(), Qos::AtMostOnce, "1").with_retain(true),
+        );
+    }
+
+    fn retained_topics(storage: &DiskStorage) -> Vec<String> {
+        let mut topics = storage
+            .retained_messages()
+            .iter()
+            .map(|msg| msg.topic().to_string())
+            .collect::<Vec<_>>();
+        topics.sort();
+        topics
+    }
+
+    /// Returns the lengths of the records in the log.
+    fn log_records(dir: &TempDir) -> Vec<usize> {
+        let data = fs::read(dir.0.join(LOG_FILE)).unwrap();
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= data.len() {
+            let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            records.push(len);
+            offset += 4 + len;
+        }
+        records
+    }
+
+    /// Writes records to the log without a snapshot, the storage is dropped without calling
+    /// `update_sessions`.
+    fn write_log(dir: &TempDir, topics: &[&str]) {
+        let storage = open(dir);
+        for topic in topics {
+            retain(&storage, topic);
+        }
+    }
+
+    fn subscribe(storage: &DiskStorage, client_id: &str, filter: &str) {
+        storage.subscribe(
+            client_id,
+            parse_filter(filter).unwrap(),
+            Qos::AtLeastOnce,
+            false,
+            false,
+            RetainHandling::OnEverySubscribe,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = TempDir::new();
+
+        {
+            let storage = open(&dir);
+            storage.create_session("a", true, 60, QueueLimits::default(), None);
+            storage.create_session("b", true, 0, QueueLimits::default(), None);
+            subscribe(&storage, "a", "test/+");
+            subscribe(&storage, "a", "$share/g/test/1");
+            subscribe(&storage, "b", "test/1");
+            storage.update_retained_message(
+                Message::new("test/2", Qos::AtMostOnce, "retained").with_retain(true),
+            );
+            storage.deliver(vec![
+                Message::new("test/1", Qos::AtLeastOnce, "1"),
+                Message::new("test/1", Qos::AtLeastOnce, "2"),
+            ]);
+            assert_eq!(storage.next_messages("a", Some(1)).len(), 1);
+            storage.add_uncompleted_message(
+                "a",
+                1.try_into().unwrap(),
+                Some(Message::new("test/3", Qos::ExactlyOnce, "3")),
+            );
+            storage.add_uncompleted_message("a", 2.try_into().unwrap(), None);
+        }
+
+        let storage = open(&dir);
+        let metrics = storage.metrics();
+        assert_eq!(metrics.session_count, 2);
+        assert_eq!(metrics.subscriptions_count, 3);
+        assert_eq!(metrics.retained_messages_count, 1);
+
+        let msgs = storage.next_messages("a", None);
+        assert_eq!(
+            msgs.iter().map(|msg| &**msg.payload()).collect::<Vec<_>>(),
+            vec![&b"1"[..], &b"2"[..], &b"2"[..]]
+        );
+        assert!(matches!(
+            storage.take_uncompleted_message("a", 1.try_into().unwrap()),
+            Some(Some(_))
+        ));
+        assert!(matches!(
+            storage.take_uncompleted_message("a", 2.try_into().unwrap()),
+            Some(None)
+        ));
+
+        // sessions with zero expiry interval are removed after the restart
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        storage.update_sessions();
+        drop(storage);
+
+        let storage = open(&dir);
+        let metrics = storage.metrics();
+        assert_eq!(metrics.session_count, 1);
+        assert_eq!(metrics.subscriptions_count, 2);
+        assert!(storage.next_messages("a", None).is_empty());
+    }
+
+    #[test]
+    fn test_restore_drop_qos0_first() {
+        let dir = TempDir::new();
+        let limits = QueueLimits {
+            max_len: None,
+            max_bytes: Some(10),
+            overflow_policy: OverflowPolicy::DropQos0First,
+        };
+        let deliver = |storage: &DiskStorage, qos, payload: &'static str| {
+            storage.deliver(vec![Message::new("test", qos, payload)]);
+        };
+        let payloads = |storage: &DiskStorage| {
+            storage
+                .next_messages("a", None)
+                .iter()
+                .map(|msg| String::from_utf8(msg.payload().to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        {
+            let storage = open(&dir);
+            storage.create_session("a", true, 60, limits, None);
+            subscribe(&storage, "a", "test");
+            deliver(&storage, Qos::AtMostOnce, "aa");
+            deliver(&storage, Qos::AtLeastOnce, "bbbbbbb");
+            // dropping "aa" is not enough, only the new message is dropped
+            deliver(&storage, Qos::AtMostOnce, "ccccc");
+            assert_eq!(storage.session("a").unwrap().queued_bytes, 9);
+        }
+
+        {
+            let storage = open(&dir);
+            assert_eq!(storage.session("a").unwrap().queued_bytes, 9);
+            // drops "aa"
+            deliver(&storage, Qos::AtLeastOnce, "ddd");
+            // there is no QoS 0 message, drops the oldest message
+            deliver(&storage, Qos::AtLeastOnce, "eeee");
+        }
+
+        let storage = open(&dir);
+        assert_eq!(payloads(&storage), vec!["ddd", "eeee"]);
+    }
+
+    #[test]
+    fn test_truncated_log() {
+        let dir = TempDir::new();
+        write_log(&dir, &["a", "b", "c"]);
+        let len = fs::metadata(dir.0.join(LOG_FILE)).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(dir.0.join(LOG_FILE))
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        // the partially written record is ignored
+        let storage = open(&dir);
+        assert_eq!(retained_topics(&storage), vec!["a", "b"]);
+        assert!(log_records(&dir).is_empty());
+
+        // the records written after the restart are not appended to the invalid tail
+        retain(&storage, "d");
+        drop(storage);
+        let storage = open(&dir);
+        assert_eq!(retained_topics(&storage), vec!["a", "b", "d"]);
+    }
+
+    #[test]
+    fn test_corrupt_log() {
+        let dir = TempDir::new();
+        write_log(&dir, &["a", "b"]);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(LOG_FILE))
+            .unwrap();
+        file.write_all(&4u32.to_le_bytes()).unwrap();
+        file.write_all(&[0xff; 4]).unwrap();
+        drop(file);
+
+        // the invalid record is dropped from the log
+        let storage = open(&dir);
+        assert_eq!(retained_topics(&storage), vec!["a", "b"]);
+        retain(&storage, "c");
+        drop(storage);
+
+        let storage = open(&dir);
+        assert_eq!(retained_topics(&storage), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = TempDir::new();
+
+        {
+            let storage = open_with_interval(&dir, 2);
+            for topic in &["a", "b", "c", "d", "e"] {
+                retain(&storage, topic);
+                storage.update_sessions();
+            }
+        }
+
+        // the snapshots are saved after "b" and "d"
+        assert_eq!(log_records(&dir).len(), 1);
+
+        let storage = open_with_interval(&dir, 2);
+        assert_eq!(retained_topics(&storage), vec!["a", "b", "c", "d", "e"]);
+        for topic in &["f", "g", "h"] {
+            retain(&storage, topic);
+            storage.update_sessions();
+        }
+        drop(storage);
+
+        // the log is truncated by the snapshot saved when opening, and after "g"
+        assert_eq!(log_records(&dir).len(), 1);
+        let storage = open_with_interval(&dir, 2);
+        assert_eq!(
+            retained_topics(&storage),
+            vec!["a", "b", "c", "d", "e", "f", "g", "h"]
+        );
+    }
+}
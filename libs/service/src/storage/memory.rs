@@ -0,0 +1,1506 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::Read;
+use std::num::{NonZeroU16, NonZeroUsize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use bytestring::ByteString;
+use codec::{LastWill, Publish, Qos, RetainHandling};
+use fnv::FnvHashMap;
+use indexmap::IndexMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use super::shared::{Member, SharedGroups};
+use super::{FilterItem, Qos2State, SessionInfo, StorageBackend, StorageMetrics, SubscriptionInfo};
+use crate::config::{OverflowPolicy, QueueLimits, SharedSubscriptionConfig};
+use crate::filter_util::{self, Filter};
+use crate::message::Message;
+use crate::state::ServiceMetrics;
+use crate::trie::Trie;
+
+/// A change to the storage state, used by [`super::DiskStorage`] to rebuild the state after a
+/// restart.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Record {
+    SetRetainedMessage {
+        topic: ByteString,
+        msg: Option<Message>,
+    },
+    CreateSession {
+        client_id: String,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    },
+    ResumeSession {
+        client_id: String,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    },
+    RemoveSession {
+        client_id: String,
+    },
+    DisconnectSession {
+        client_id: String,
+        session_expiry_interval: u32,
+        last_will_timeout: Option<SystemTime>,
+        remove_timeout: SystemTime,
+    },
+    LastWillSent {
+        client_id: String,
+    },
+    SessionExpired {
+        client_id: String,
+    },
+    Subscribe {
+        client_id: String,
+        filter: String,
+        filter_item: FilterItem,
+    },
+    Unsubscribe {
+        client_id: String,
+        filter: String,
+    },
+    AddMessage {
+        client_id: String,
+        msg: Message,
+    },
+    TakeMessages {
+        client_id: String,
+        count: usize,
+    },
+    RemoveMessage {
+        client_id: String,
+        index: usize,
+    },
+    AddInflightPubPacket {
+        client_id: String,
+        publish: Publish,
+        shared_msg: Option<Message>,
+    },
+    RemoveInflightPubPacket {
+        client_id: String,
+    },
+    RemoveInflightPubPacketById {
+        client_id: String,
+        packet_id: NonZeroU16,
+    },
+    SetQos2Recorded {
+        client_id: String,
+        packet_id: NonZeroU16,
+    },
+    RemoveQos2State {
+        client_id: String,
+        packet_id: NonZeroU16,
+    },
+    AddUncompletedMessage {
+        client_id: String,
+        packet_id: NonZeroU16,
+        msg: Option<Message>,
+    },
+    TakeUncompletedMessage {
+        client_id: String,
+        packet_id: NonZeroU16,
+    },
+}
+
+pub(super) trait Journal: Send + Sync {
+    fn append(&self, record: Record);
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    queue: VecDeque<Message>,
+    queue_bytes: usize,
+    queue_limits: QueueLimits,
+    #[serde(skip)]
+    queue_overflowed: bool,
+    #[serde(skip)]
+    notify: Arc<Notify>,
+    last_will: Option<LastWill>,
+    session_expiry_interval: u32,
+    inflight_pub_packets: VecDeque<Publish>,
+    /// The routed messages of the inflight packets queued through a shared subscription.
+    inflight_shared_messages: FnvHashMap<NonZeroU16, Message>,
+    inflight_qos2_messages: FnvHashMap<NonZeroU16, Qos2State>,
+    /// The incoming QoS 2 messages waiting for PUBREL, `None` if the message is not routed.
+    uncompleted_messages: FnvHashMap<NonZeroU16, Option<Message>>,
+    last_will_timeout_key: Option<TimeoutKey>,
+    remove_timeout_key: Option<TimeoutKey>,
+}
+
+impl Session {
+    fn new(
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    ) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queue_bytes: 0,
+            queue_limits,
+            queue_overflowed: false,
+            notify: Arc::new(Notify::new()),
+            last_will,
+            session_expiry_interval,
+            inflight_pub_packets: VecDeque::default(),
+            inflight_shared_messages: FnvHashMap::default(),
+            inflight_qos2_messages: FnvHashMap::default(),
+            uncompleted_messages: FnvHashMap::default(),
+            last_will_timeout_key: None,
+            remove_timeout_key: None,
+        }
+    }
+
+    fn info(&self, client_id: &str) -> SessionInfo {
+        SessionInfo {
+            client_id: client_id.to_string(),
+            connected: self.remove_timeout_key.is_none(),
+            session_expiry_interval: self.session_expiry_interval,
+            queued_messages: self.queue.len(),
+            queued_bytes: self.queue_bytes,
+            inflight_messages: self.inflight_pub_packets.len(),
+        }
+    }
+
+    #[inline]
+    fn is_queue_overflowed(&self) -> bool {
+        self.exceeds_queue_limits(self.queue.len(), self.queue_bytes)
+    }
+
+    #[inline]
+    fn exceeds_queue_limits(&self, len: usize, bytes: usize) -> bool {
+        matches!(self.queue_limits.max_len, Some(max_len) if len > max_len)
+            || matches!(self.queue_limits.max_bytes, Some(max_bytes) if bytes > max_bytes)
+    }
+
+    #[inline]
+    fn remove_message(&mut self, index: usize) -> Option<Message> {
+        let msg = self.queue.remove(index)?;
+        self.queue_bytes -= msg.payload().len();
+        Some(msg)
+    }
+
+    fn add_inflight_pub_packet(&mut self, publish: Publish, shared_msg: Option<Message>) {
+        if let Some(packet_id) = publish.packet_id {
+            if publish.qos == Qos::ExactlyOnce {
+                self.inflight_qos2_messages
+                    .insert(packet_id, Qos2State::Published);
+            }
+            if let Some(msg) = shared_msg {
+                self.inflight_shared_messages.insert(packet_id, msg);
+            }
+        }
+        self.inflight_pub_packets.push_back(publish);
+    }
+
+    fn pop_inflight_pub_packet(&mut self) -> Option<Publish> {
+        let publish = self.inflight_pub_packets.pop_front()?;
+        if let Some(packet_id) = publish.packet_id {
+            self.inflight_shared_messages.remove(&packet_id);
+        }
+        Some(publish)
+    }
+
+    fn remove_inflight_pub_packet(&mut self, packet_id: NonZeroU16) -> Option<Publish> {
+        let index = self
+            .inflight_pub_packets
+            .iter()
+            .position(|publish| publish.packet_id == Some(packet_id))?;
+        self.inflight_shared_messages.remove(&packet_id);
+        self.inflight_qos2_messages.remove(&packet_id);
+        self.inflight_pub_packets.remove(index)
+    }
+
+    /// Appends a message to the queue and applies the overflow policy if the queue limits are
+    /// exceeded.
+    ///
+    /// Returns whether the message was added and the number of dropped messages. The queue is
+    /// unchanged if the message is not added, so [`Record::AddMessage`] replays the changes.
+    fn push_message(&mut self, msg: Message) -> (bool, usize) {
+        let too_large = matches!(self.queue_limits.max_len, Some(0))
+            || matches!(self.queue_limits.max_bytes, Some(max_bytes) if msg.payload().len() > max_bytes);
+
+        self.queue_bytes += msg.payload().len();
+        self.queue.push_back(msg);
+        if !self.is_queue_overflowed() {
+            return (true, 0);
+        }
+
+        let mut dropped = 0;
+
+        match self.queue_limits.overflow_policy {
+            OverflowPolicy::DropNewest | OverflowPolicy::Disconnect => {
+                self.remove_message(self.queue.len() - 1);
+                if self.queue_limits.overflow_policy == OverflowPolicy::Disconnect {
+                    self.queue_overflowed = true;
+                }
+                return (false, 1);
+            }
+            _ if too_large => {
+                self.remove_message(self.queue.len() - 1);
+                return (false, 1);
+            }
+            OverflowPolicy::DropQos0First => {
+                let new_index = self.queue.len() - 1;
+
+                // the number of the oldest QoS 0 messages to drop to make room for the new message
+                let mut count = 0;
+                let mut len = self.queue.len();
+                let mut bytes = self.queue_bytes;
+                for msg in self.queue.iter().take(new_index) {
+                    if !self.exceeds_queue_limits(len, bytes) {
+                        break;
+                    }
+                    if msg.qos() == Qos::AtMostOnce {
+                        count += 1;
+                        len -= 1;
+                        bytes -= msg.payload().len();
+                    }
+                }
+
+                if self.exceeds_queue_limits(len, bytes)
+                    && self.queue[new_index].qos() == Qos::AtMostOnce
+                {
+                    // dropping the new message is enough to keep the limits
+                    self.remove_message(new_index);
+                    return (false, 1);
+                }
+
+                let mut index = 0;
+                while dropped < count {
+                    if self.queue[index].qos() == Qos::AtMostOnce {
+                        self.remove_message(index);
+                        dropped += 1;
+                    } else {
+                        index += 1;
+                    }
+                }
+            }
+            OverflowPolicy::DropOldest => {}
+        }
+
+        while self.is_queue_overflowed() {
+            self.remove_message(0);
+            dropped += 1;
+        }
+
+        (true, dropped)
+    }
+
+    #[inline]
+    fn add_message<'a>(
+        &mut self,
+        msg: &Message,
+        filter_items: impl IntoIterator<Item = &'a FilterItem>,
+        share_name: Option<&str>,
+        service_metrics: &ServiceMetrics,
+    ) -> Option<&Message> {
+        let mut filter_items = filter_items.into_iter();
+        let first_item = filter_items.next()?;
+        let mut qos = first_item.qos;
+        let mut retain_as_published = first_item.retain_as_published;
+        let mut ids = first_item.id.into_iter().collect::<Vec<_>>();
+
+        for item in filter_items {
+            // When Clients make subscriptions with Topic Filters that include wildcards, it is possible
+            // for a Client’s subscriptions to overlap so that a published message might match multiple filters.
+            // In this case the Server MUST deliver the message to the Client respecting the maximum QoS of all
+            // the matching subscriptions [MQTT-3.3.4-2].
+            qos = qos.max(item.qos);
+
+            retain_as_published &= item.retain_as_published;
+
+            // If the Client specified a Subscription Identifier for any of the overlapping
+            // subscriptions the Server MUST send those Subscription Identifiers in the message
+            // which is published as the result of the subscriptions [MQTT-3.3.4-3].
+            //
+            // If the Server sends a single copy of the message it MUST include in the PUBLISH packet
+            // the Subscription Identifiers for all matching subscriptions which have a Subscription Identifiers,
+            // their order is not significant [MQTT-3.3.4-4].
+            ids.extend(item.id.into_iter());
+        }
+
+        let mut new_msg = Message::new(
+            msg.topic().clone(),
+            msg.qos().min(qos),
+            msg.payload().clone(),
+        )
+        .with_properties({
+            let mut properties = msg.properties().clone();
+            properties.subscription_identifiers = ids;
+            properties
+        });
+
+        if retain_as_published {
+            new_msg = new_msg.with_retain(msg.is_retain());
+        }
+
+        if let Some(share_name) = share_name {
+            new_msg = new_msg.with_share_name(share_name);
+            for client_id in msg.refused_by() {
+                new_msg = new_msg.with_refused_by(client_id.clone());
+            }
+        }
+
+        let (added, dropped) = self.push_message(new_msg);
+        if dropped > 0 {
+            service_metrics.inc_msg_dropped(dropped);
+        }
+        if added || self.queue_overflowed {
+            self.notify.notify_one();
+        }
+        if added {
+            self.queue.back()
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+struct TimeoutKey {
+    client_id: String,
+    timeout: SystemTime,
+}
+
+impl PartialOrd for TimeoutKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.timeout.cmp(&other.timeout) {
+            Ordering::Less => Some(Ordering::Less),
+            Ordering::Greater => Some(Ordering::Greater),
+            Ordering::Equal => self.client_id.partial_cmp(&other.client_id),
+        }
+    }
+}
+
+impl Ord for TimeoutKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.partial_cmp(other).unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    sessions: Vec<(&'a str, &'a Session)>,
+    subscriptions: Vec<(&'a str, String, &'a FilterItem)>,
+    retained_messages: Vec<&'a Message>,
+    clients_expired: usize,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    sessions: Vec<(String, Session)>,
+    subscriptions: Vec<(String, String, FilterItem)>,
+    retained_messages: Vec<Message>,
+    clients_expired: usize,
+}
+
+#[derive(Default)]
+struct StorageInner {
+    sessions: HashMap<String, RwLock<Session>>,
+    filter_tree: Trie,
+    send_last_will_timeout: BTreeSet<TimeoutKey>,
+    remove_timeout: BTreeSet<TimeoutKey>,
+    clients_expired: usize,
+    shared_groups: SharedGroups,
+    service_metrics: Arc<ServiceMetrics>,
+    journal: Option<Arc<dyn Journal>>,
+}
+
+impl StorageInner {
+    #[inline]
+    fn record(&self, f: impl FnOnce() -> Record) {
+        if let Some(journal) = &self.journal {
+            journal.append(f());
+        }
+    }
+
+    pub fn deliver(&self, msgs: impl IntoIterator<Item = Message>) {
+        for msg in msgs {
+            if msg.is_expired() {
+                continue;
+            }
+
+            for (client_id, filter_items) in self.filter_tree.matches(msg.topic()) {
+                let filter_items = filter_items.into_iter().filter(|filter_item| {
+                    // If no local is true, Application Messages MUST NOT be forwarded to a connection with
+                    // a ClientID equal to the ClientID of the publishing connection [MQTT-3.8.3-3]
+                    !filter_item.no_local || msg.from_client_id().map(|s| &**s) != Some(client_id)
+                });
+
+                if let Some(session) = self.sessions.get(client_id) {
+                    let mut session = session.write();
+                    if let Some(msg) =
+                        session.add_message(&msg, filter_items, None, &self.service_metrics)
+                    {
+                        self.record(|| Record::AddMessage {
+                            client_id: client_id.to_string(),
+                            msg: msg.clone(),
+                        });
+                    }
+                }
+            }
+
+            let mut shared_matched: HashMap<&str, (&str, Vec<&FilterItem>)> = HashMap::new();
+
+            for (share_name, mut share_matches) in self.filter_tree.matches_shared(msg.topic()) {
+                let members = self.shared_members(&share_matches);
+                let idx = self.shared_groups.select(share_name, &msg, &members);
+                let (client_id, filter_items) = share_matches.swap_remove_index(idx).unwrap();
+                shared_matched
+                    .entry(client_id)
+                    .or_insert_with(|| (share_name, Vec::new()))
+                    .1
+                    .extend(filter_items);
+            }
+
+            for (client_id, (share_name, filter_items)) in shared_matched {
+                if let Some(session) = self.sessions.get(client_id) {
+                    let mut session = session.write();
+                    if let Some(msg) = session.add_message(
+                        &msg,
+                        filter_items,
+                        Some(share_name),
+                        &self.service_metrics,
+                    ) {
+                        self.record(|| Record::AddMessage {
+                            client_id: client_id.to_string(),
+                            msg: msg.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn shared_members<'a>(
+        &self,
+        share_matches: &IndexMap<&'a str, Vec<&FilterItem>>,
+    ) -> Vec<Member<'a>> {
+        share_matches
+            .keys()
+            .map(|client_id| match self.sessions.get(*client_id) {
+                Some(session) => {
+                    let session = session.read();
+                    Member {
+                        client_id,
+                        online: session.remove_timeout_key.is_none(),
+                        queued: session.queue.len() + session.inflight_pub_packets.len(),
+                    }
+                }
+                None => Member {
+                    client_id,
+                    online: false,
+                    queued: 0,
+                },
+            })
+            .collect()
+    }
+
+    /// Adds a message of a share group to a member other than `client_id` that has not refused it.
+    ///
+    /// Returns `false` if there is no other member, or no other online member if `online_only` is
+    /// `true`.
+    fn dispatch_shared_message(
+        &self,
+        client_id: &str,
+        share_name: &str,
+        msg: &Message,
+        online_only: bool,
+    ) -> bool {
+        let mut share_matches = match self
+            .filter_tree
+            .matches_shared(msg.topic())
+            .find(|(name, _)| *name == share_name)
+        {
+            Some((_, share_matches)) => share_matches,
+            None => return false,
+        };
+        share_matches.shift_remove(client_id);
+        share_matches.retain(|member_id, _| {
+            !msg.refused_by()
+                .iter()
+                .any(|refused| &**refused == *member_id)
+        });
+
+        let members = self.shared_members(&share_matches);
+        if members.is_empty() || (online_only && !members.iter().any(|member| member.online)) {
+            return false;
+        }
+
+        let idx = self.shared_groups.select(share_name, msg, &members);
+        let (member_id, filter_items) = share_matches.get_index(idx).unwrap();
+        if let Some(session) = self.sessions.get(*member_id) {
+            let mut session = session.write();
+            if let Some(msg) = session.add_message(
+                msg,
+                filter_items.iter().copied(),
+                Some(share_name),
+                &self.service_metrics,
+            ) {
+                self.record(|| Record::AddMessage {
+                    client_id: member_id.to_string(),
+                    msg: msg.clone(),
+                });
+            }
+        }
+        true
+    }
+
+    /// Moves the messages of shared subscriptions that are queued or inflight in the session to
+    /// the other members of their share groups.
+    ///
+    /// If `online_only` is `true`, a message is kept if its group has no other online member.
+    fn redispatch_shared_messages(&self, client_id: &str, online_only: bool) {
+        let mut session = match self.sessions.get(client_id) {
+            Some(session) => session.write(),
+            None => return,
+        };
+
+        let mut index = 0;
+        while index < session.queue.len() {
+            let msg = &session.queue[index];
+            let dispatched = match msg.share_name() {
+                Some(share_name) => {
+                    self.dispatch_shared_message(client_id, share_name, msg, online_only)
+                }
+                None => false,
+            };
+            if dispatched {
+                self.record(|| Record::RemoveMessage {
+                    client_id: client_id.to_string(),
+                    index,
+                });
+                session.remove_message(index);
+            } else {
+                index += 1;
+            }
+        }
+
+        let mut packet_ids = Vec::new();
+        for publish in &session.inflight_pub_packets {
+            let packet_id = match publish.packet_id {
+                Some(packet_id) => packet_id,
+                None => continue,
+            };
+            let (msg, share_name) = match session
+                .inflight_shared_messages
+                .get(&packet_id)
+                .and_then(|msg| Some((msg, msg.share_name()?)))
+            {
+                Some(res) => res,
+                None => continue,
+            };
+
+            // the client has already received the QoS 2 message
+            if session.inflight_qos2_messages.get(&packet_id) == Some(&Qos2State::Recorded) {
+                continue;
+            }
+
+            if self.dispatch_shared_message(client_id, share_name, msg, online_only) {
+                packet_ids.push(packet_id);
+            }
+        }
+
+        for packet_id in packet_ids {
+            self.record(|| Record::RemoveInflightPubPacketById {
+                client_id: client_id.to_string(),
+                packet_id,
+            });
+            session.remove_inflight_pub_packet(packet_id);
+        }
+    }
+
+    fn remove_session(&mut self, client_id: &str) {
+        if let Some(session) = self.sessions.remove(client_id) {
+            let session = session.into_inner();
+            if let Some(key) = &session.last_will_timeout_key {
+                self.send_last_will_timeout.remove(key);
+            }
+            if let Some(key) = &session.remove_timeout_key {
+                self.remove_timeout.remove(key);
+            }
+        }
+        self.filter_tree.unsubscribe_all(client_id);
+    }
+
+    fn disconnect_session(
+        &mut self,
+        client_id: &str,
+        session_expiry_interval: u32,
+        last_will_timeout: Option<SystemTime>,
+        remove_timeout: SystemTime,
+    ) {
+        if let Some(session) = self.sessions.get(client_id) {
+            let mut session = session.write();
+            session.session_expiry_interval = session_expiry_interval;
+
+            if let Some(timeout) = last_will_timeout {
+                let key = TimeoutKey {
+                    client_id: client_id.to_string(),
+                    timeout,
+                };
+                self.send_last_will_timeout.insert(key.clone());
+                session.last_will_timeout_key = Some(key);
+            }
+
+            let key = TimeoutKey {
+                client_id: client_id.to_string(),
+                timeout: remove_timeout,
+            };
+            self.remove_timeout.insert(key.clone());
+            session.remove_timeout_key = Some(key);
+        }
+    }
+
+    /// Starts the last will and expiry timers of a disconnected session.
+    ///
+    /// Returns `false` if the session does not exist.
+    fn start_session_expiry(&mut self, client_id: &str, session_expiry_interval: u32) -> bool {
+        let now = SystemTime::now();
+
+        let last_will_delay = match self.sessions.get(client_id) {
+            Some(session) => session.read().last_will.as_ref().map(|last_will| {
+                last_will
+                    .properties
+                    .delay_interval
+                    .unwrap_or_default()
+                    .min(session_expiry_interval)
+            }),
+            None => return false,
+        };
+        let last_will_timeout =
+            last_will_delay.map(|interval| now + Duration::from_secs(interval as u64));
+        let remove_timeout = now + Duration::from_secs(session_expiry_interval as u64);
+
+        self.record(|| Record::DisconnectSession {
+            client_id: client_id.to_string(),
+            session_expiry_interval,
+            last_will_timeout,
+            remove_timeout,
+        });
+        self.disconnect_session(
+            client_id,
+            session_expiry_interval,
+            last_will_timeout,
+            remove_timeout,
+        );
+        true
+    }
+
+    fn resume_session(
+        &mut self,
+        client_id: &str,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    ) {
+        let (last_will_timeout_key, remove_timeout_key) = match self.sessions.get(client_id) {
+            Some(session) => {
+                let mut session = session.write();
+                session.last_will = last_will;
+                session.session_expiry_interval = session_expiry_interval;
+                session.queue_limits = queue_limits;
+                session.queue_overflowed = false;
+                (
+                    session.last_will_timeout_key.take(),
+                    session.remove_timeout_key.take(),
+                )
+            }
+            None => return,
+        };
+
+        if let Some(key) = last_will_timeout_key {
+            self.send_last_will_timeout.remove(&key);
+        }
+        if let Some(key) = remove_timeout_key {
+            self.remove_timeout.remove(&key);
+        }
+    }
+
+    fn take_last_will(&mut self, client_id: &str) -> Option<LastWill> {
+        let session = self.sessions.get(client_id)?;
+        let mut session = session.write();
+        if let Some(key) = session.last_will_timeout_key.take() {
+            self.send_last_will_timeout.remove(&key);
+        }
+        session.last_will.take()
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::SetRetainedMessage { topic, msg } => {
+                self.filter_tree.set_retained_message(topic, msg);
+            }
+            Record::CreateSession {
+                client_id,
+                session_expiry_interval,
+                queue_limits,
+                last_will,
+            } => {
+                self.sessions.insert(
+                    client_id,
+                    RwLock::new(Session::new(
+                        session_expiry_interval,
+                        queue_limits,
+                        last_will,
+                    )),
+                );
+            }
+            Record::ResumeSession {
+                client_id,
+                session_expiry_interval,
+                queue_limits,
+                last_will,
+            } => self.resume_session(&client_id, session_expiry_interval, queue_limits, last_will),
+            Record::RemoveSession { client_id } => self.remove_session(&client_id),
+            Record::DisconnectSession {
+                client_id,
+                session_expiry_interval,
+                last_will_timeout,
+                remove_timeout,
+            } => self.disconnect_session(
+                &client_id,
+                session_expiry_interval,
+                last_will_timeout,
+                remove_timeout,
+            ),
+            Record::LastWillSent { client_id } => {
+                self.take_last_will(&client_id);
+            }
+            Record::SessionExpired { client_id } => {
+                self.remove_session(&client_id);
+                self.clients_expired += 1;
+            }
+            Record::Subscribe {
+                client_id,
+                filter,
+                filter_item,
+            } => {
+                if let Some(filter) = filter_util::parse_filter(&filter) {
+                    self.filter_tree.subscribe(filter, client_id, filter_item);
+                }
+            }
+            Record::Unsubscribe { client_id, filter } => {
+                if let Some(filter) = filter_util::parse_filter(&filter) {
+                    self.filter_tree.unsubscribe(filter, &client_id);
+                }
+            }
+            Record::AddMessage { client_id, msg } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().push_message(msg);
+                }
+            }
+            Record::TakeMessages { client_id, count } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    let mut session = session.write();
+                    for _ in 0..count {
+                        session.remove_message(0);
+                    }
+                }
+            }
+            Record::RemoveMessage { client_id, index } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().remove_message(index);
+                }
+            }
+            Record::AddInflightPubPacket {
+                client_id,
+                publish,
+                shared_msg,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().add_inflight_pub_packet(publish, shared_msg);
+                }
+            }
+            Record::RemoveInflightPubPacket { client_id } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().pop_inflight_pub_packet();
+                }
+            }
+            Record::RemoveInflightPubPacketById {
+                client_id,
+                packet_id,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().remove_inflight_pub_packet(packet_id);
+                }
+            }
+            Record::SetQos2Recorded {
+                client_id,
+                packet_id,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session
+                        .write()
+                        .inflight_qos2_messages
+                        .insert(packet_id, Qos2State::Recorded);
+                }
+            }
+            Record::RemoveQos2State {
+                client_id,
+                packet_id,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().inflight_qos2_messages.remove(&packet_id);
+                }
+            }
+            Record::AddUncompletedMessage {
+                client_id,
+                packet_id,
+                msg,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().uncompleted_messages.insert(packet_id, msg);
+                }
+            }
+            Record::TakeUncompletedMessage {
+                client_id,
+                packet_id,
+            } => {
+                if let Some(session) = self.sessions.get(&client_id) {
+                    session.write().uncompleted_messages.remove(&packet_id);
+                }
+            }
+        }
+    }
+}
+
+#[inline]
+fn filter_to_string(filter: Filter<'_>) -> String {
+    match filter.share_name {
+        Some(share_name) => format!("$share/{}/{}", share_name, filter.path),
+        None => filter.path.to_string(),
+    }
+}
+
+fn subscription_info(
+    client_id: &str,
+    filter: String,
+    filter_item: &FilterItem,
+) -> SubscriptionInfo {
+    SubscriptionInfo {
+        client_id: client_id.to_string(),
+        filter,
+        qos: filter_item.qos,
+        no_local: filter_item.no_local,
+        retain_as_published: filter_item.retain_as_published,
+        retain_handling: filter_item.retain_handling,
+        id: filter_item.id,
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    inner: RwLock<StorageInner>,
+}
+
+impl MemoryStorage {
+    pub fn new(
+        shared_subscriptions: SharedSubscriptionConfig,
+        service_metrics: Arc<ServiceMetrics>,
+    ) -> Self {
+        Self {
+            inner: RwLock::new(StorageInner {
+                shared_groups: SharedGroups::new(shared_subscriptions),
+                service_metrics,
+                ..StorageInner::default()
+            }),
+        }
+    }
+
+    /// Restores the state from a snapshot written by [`MemoryStorage::save_snapshot`].
+    pub(super) fn load_snapshot(
+        reader: impl Read,
+        shared_subscriptions: SharedSubscriptionConfig,
+        service_metrics: Arc<ServiceMetrics>,
+    ) -> Result<Self> {
+        let snapshot: Snapshot = bincode::deserialize_from(reader)?;
+        let mut inner = StorageInner {
+            clients_expired: snapshot.clients_expired,
+            shared_groups: SharedGroups::new(shared_subscriptions),
+            service_metrics,
+            ..StorageInner::default()
+        };
+
+        for (client_id, session) in snapshot.sessions {
+            if let Some(key) = &session.last_will_timeout_key {
+                inner.send_last_will_timeout.insert(key.clone());
+            }
+            if let Some(key) = &session.remove_timeout_key {
+                inner.remove_timeout.insert(key.clone());
+            }
+            inner.sessions.insert(client_id, RwLock::new(session));
+        }
+
+        for (client_id, filter, filter_item) in snapshot.subscriptions {
+            if let Some(filter) = filter_util::parse_filter(&filter) {
+                inner.filter_tree.subscribe(filter, client_id, filter_item);
+            }
+        }
+
+        for msg in snapshot.retained_messages {
+            inner
+                .filter_tree
+                .set_retained_message(msg.topic().clone(), Some(msg));
+        }
+
+        Ok(Self {
+            inner: RwLock::new(inner),
+        })
+    }
+
+    /// Serializes the state and passes it to `f` while changes are still blocked, so that `f`
+    /// can order it with the records of the journal.
+    pub(super) fn save_snapshot(&self, f: impl FnOnce(Vec<u8>)) -> Result<()> {
+        let inner = self.inner.write();
+        let sessions = inner
+            .sessions
+            .iter()
+            .map(|(client_id, session)| (client_id.as_str(), session.read()))
+            .collect::<Vec<_>>();
+        let snapshot = SnapshotRef {
+            sessions: sessions
+                .iter()
+                .map(|(client_id, session)| (*client_id, &**session))
+                .collect(),
+            subscriptions: inner.filter_tree.subscriptions(),
+            retained_messages: inner.filter_tree.retained_messages(),
+            clients_expired: inner.clients_expired,
+        };
+        f(bincode::serialize(&snapshot)?);
+        Ok(())
+    }
+
+    pub(super) fn apply(&self, record: Record) {
+        self.inner.write().apply(record);
+    }
+
+    pub(super) fn set_journal(&mut self, journal: Arc<dyn Journal>) {
+        self.inner.get_mut().journal = Some(journal);
+    }
+
+    /// Starts the expiry timers for sessions that were connected when the state was saved.
+    pub(super) fn disconnect_all_sessions(&self) {
+        let mut inner = self.inner.write();
+        let sessions = inner
+            .sessions
+            .iter()
+            .filter_map(|(client_id, session)| {
+                let session = session.read();
+                if session.remove_timeout_key.is_none() {
+                    Some((client_id.clone(), session.session_expiry_interval))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for (client_id, session_expiry_interval) in sessions {
+            inner.start_session_expiry(&client_id, session_expiry_interval);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+impl StorageBackend for MemoryStorage {
+    fn update_retained_message(&self, msg: Message) {
+        let mut inner = self.inner.write();
+        let topic = msg.topic().clone();
+        let msg = if !msg.is_empty() { Some(msg) } else { None };
+        inner.record(|| Record::SetRetainedMessage {
+            topic: topic.clone(),
+            msg: msg.clone(),
+        });
+        inner.filter_tree.set_retained_message(topic, msg);
+    }
+
+    fn create_session(
+        &self,
+        client_id: &str,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    ) -> (bool, Arc<Notify>) {
+        let mut inner = self.inner.write();
+        let mut session_present = false;
+
+        if !clean_start {
+            if inner.sessions.contains_key(client_id) {
+                session_present = true;
+                inner.record(|| Record::ResumeSession {
+                    client_id: client_id.to_string(),
+                    session_expiry_interval,
+                    queue_limits,
+                    last_will: last_will.clone(),
+                });
+                inner.resume_session(
+                    client_id,
+                    session_expiry_interval,
+                    queue_limits,
+                    last_will.clone(),
+                );
+            }
+        } else if inner.sessions.contains_key(client_id) {
+            inner.redispatch_shared_messages(client_id, false);
+            inner.record(|| Record::RemoveSession {
+                client_id: client_id.to_string(),
+            });
+            inner.remove_session(client_id);
+        }
+
+        if !session_present {
+            inner.record(|| Record::CreateSession {
+                client_id: client_id.to_string(),
+                session_expiry_interval,
+                queue_limits,
+                last_will: last_will.clone(),
+            });
+            inner.sessions.insert(
+                client_id.to_string(),
+                RwLock::new(Session::new(
+                    session_expiry_interval,
+                    queue_limits,
+                    last_will,
+                )),
+            );
+        }
+
+        let notify = inner.sessions.get(client_id).unwrap().read().notify.clone();
+        (session_present, notify)
+    }
+
+    fn disconnect_session(&self, client_id: &str, session_expiry_interval: u32) {
+        let mut inner = self.inner.write();
+        if inner.start_session_expiry(client_id, session_expiry_interval) {
+            inner.redispatch_shared_messages(client_id, true);
+        }
+    }
+
+    fn update_sessions(&self) {
+        let mut inner = self.inner.write();
+        let now = SystemTime::now();
+        let mut last_wills = Vec::new();
+
+        loop {
+            match inner.send_last_will_timeout.iter().next().cloned() {
+                Some(key) if key.timeout < now => {
+                    inner.record(|| Record::LastWillSent {
+                        client_id: key.client_id.clone(),
+                    });
+                    if let Some(last_will) = inner.take_last_will(&key.client_id) {
+                        last_wills.push((key.client_id.clone(), last_will));
+                    }
+                    inner.send_last_will_timeout.remove(&key);
+                }
+                _ => break,
+            }
+        }
+
+        loop {
+            match inner.remove_timeout.iter().next().cloned() {
+                Some(key) if key.timeout < now => {
+                    tracing::debug!(
+                        client_id = %key.client_id,
+                        "session timeout",
+                    );
+
+                    inner.redispatch_shared_messages(&key.client_id, false);
+                    inner.record(|| Record::SessionExpired {
+                        client_id: key.client_id.clone(),
+                    });
+                    inner.remove_session(&key.client_id);
+                    inner.remove_timeout.remove(&key);
+                    inner.clients_expired += 1;
+                }
+                _ => break,
+            }
+        }
+
+        for (client_id, last_will) in last_wills {
+            tracing::debug!(
+                publisher = %client_id,
+                topic = %last_will.topic,
+                "send last will message",
+            );
+
+            inner.deliver(std::iter::once(Message::from_last_will(last_will)));
+        }
+    }
+
+    fn subscribe(
+        &self,
+        client_id: &str,
+        filter: Filter<'_>,
+        qos: Qos,
+        no_local: bool,
+        retain_as_published: bool,
+        retain_handling: RetainHandling,
+        id: Option<NonZeroUsize>,
+    ) {
+        let mut inner = self.inner.write();
+        let filter_item = FilterItem {
+            qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+            id,
+        };
+
+        inner.record(|| Record::Subscribe {
+            client_id: client_id.to_string(),
+            filter: filter_to_string(filter),
+            filter_item,
+        });
+        let is_new_subscribe = inner
+            .filter_tree
+            .subscribe(filter, client_id.to_string(), filter_item)
+            .is_none();
+
+        if filter.share_name.is_none() {
+            // send retained messages
+            let publish_retain = matches!(
+                (retain_handling, is_new_subscribe),
+                (RetainHandling::OnEverySubscribe, _) | (RetainHandling::OnNewSubscribe, true)
+            );
+
+            if publish_retain {
+                for msg in inner.filter_tree.matches_retained_messages(filter.path) {
+                    if msg.is_expired() {
+                        continue;
+                    }
+
+                    if filter_item.no_local && msg.from_client_id().map(|s| &**s) == Some(client_id)
+                    {
+                        // If no local is true, Application Messages MUST NOT be forwarded to a connection with
+                        // a ClientID equal to the ClientID of the publishing connection [MQTT-3.8.3-3]
+                        continue;
+                    }
+
+                    if let Some(session) = inner.sessions.get(client_id) {
+                        let mut session = session.write();
+                        if let Some(msg) = session.add_message(
+                            msg,
+                            std::iter::once(&filter_item),
+                            None,
+                            &inner.service_metrics,
+                        ) {
+                            inner.record(|| Record::AddMessage {
+                                client_id: client_id.to_string(),
+                                msg: msg.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn unsubscribe(&self, client_id: &str, filter: Filter<'_>) -> bool {
+        let mut inner = self.inner.write();
+        if inner.filter_tree.unsubscribe(filter, client_id).is_some() {
+            inner.record(|| Record::Unsubscribe {
+                client_id: client_id.to_string(),
+                filter: filter_to_string(filter),
+            });
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_messages(&self, client_id: &str, limit: Option<usize>) -> Vec<Message> {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        let mut limit = limit.unwrap_or(usize::MAX);
+        let mut res = Vec::new();
+
+        if limit > 0 {
+            while let Some(msg) = session.remove_message(0) {
+                res.push(msg);
+                limit -= 1;
+                if limit == 0 {
+                    break;
+                }
+            }
+        }
+
+        if !res.is_empty() {
+            inner.record(|| Record::TakeMessages {
+                client_id: client_id.to_string(),
+                count: res.len(),
+            });
+        }
+        res
+    }
+
+    #[inline]
+    fn deliver(&self, msgs: Vec<Message>) {
+        self.inner.read().deliver(msgs);
+    }
+
+    fn redispatch_refused_message(&self, client_id: &str, msg: Message) -> bool {
+        let share_name = match msg.share_name() {
+            Some(share_name) => share_name.clone(),
+            None => return false,
+        };
+        let msg = msg.with_refused_by(client_id);
+        self.inner
+            .read()
+            .dispatch_shared_message(client_id, &share_name, &msg, false)
+    }
+
+    fn add_inflight_pub_packet(
+        &self,
+        client_id: &str,
+        publish: Publish,
+        shared_msg: Option<Message>,
+    ) {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        inner.record(|| Record::AddInflightPubPacket {
+            client_id: client_id.to_string(),
+            publish: publish.clone(),
+            shared_msg: shared_msg.clone(),
+        });
+        session.add_inflight_pub_packet(publish, shared_msg);
+    }
+
+    fn get_inflight_pub_packets(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        remove: bool,
+    ) -> Option<Publish> {
+        let inner = self.inner.read();
+        if remove {
+            let mut session = inner.sessions.get(client_id).unwrap().write();
+            if session
+                .inflight_pub_packets
+                .front()
+                .map(|publish| publish.packet_id == Some(packet_id))
+                .unwrap_or_default()
+            {
+                inner.record(|| Record::RemoveInflightPubPacket {
+                    client_id: client_id.to_string(),
+                });
+                session.pop_inflight_pub_packet()
+            } else {
+                None
+            }
+        } else {
+            let session = inner.sessions.get(client_id).unwrap().read();
+            session
+                .inflight_pub_packets
+                .front()
+                .filter(|publish| publish.packet_id == Some(packet_id))
+                .cloned()
+        }
+    }
+
+    fn get_all_inflight_pub_packets(&self, client_id: &str) -> Vec<Publish> {
+        let inner = self.inner.read();
+        let session = inner.sessions.get(client_id).unwrap().read();
+        session.inflight_pub_packets.iter().cloned().collect()
+    }
+
+    fn take_queue_overflowed(&self, client_id: &str) -> bool {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        std::mem::take(&mut session.queue_overflowed)
+    }
+
+    fn get_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State> {
+        let inner = self.inner.read();
+        let session = inner.sessions.get(client_id).unwrap().read();
+        session.inflight_qos2_messages.get(&packet_id).copied()
+    }
+
+    fn set_qos2_recorded(&self, client_id: &str, packet_id: NonZeroU16) -> bool {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        match session.inflight_qos2_messages.get_mut(&packet_id) {
+            Some(state) if *state == Qos2State::Published => {
+                *state = Qos2State::Recorded;
+                inner.record(|| Record::SetQos2Recorded {
+                    client_id: client_id.to_string(),
+                    packet_id,
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State> {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        let res = session.inflight_qos2_messages.remove(&packet_id);
+        if res.is_some() {
+            inner.record(|| Record::RemoveQos2State {
+                client_id: client_id.to_string(),
+                packet_id,
+            });
+        }
+        res
+    }
+
+    fn add_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        msg: Option<Message>,
+    ) -> bool {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        match session.uncompleted_messages.entry(packet_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                inner.record(|| Record::AddUncompletedMessage {
+                    client_id: client_id.to_string(),
+                    packet_id,
+                    msg: msg.clone(),
+                });
+                entry.insert(msg);
+                true
+            }
+        }
+    }
+
+    fn has_uncompleted_message(&self, client_id: &str, packet_id: NonZeroU16) -> bool {
+        let inner = self.inner.read();
+        let session = inner.sessions.get(client_id).unwrap().read();
+        session.uncompleted_messages.contains_key(&packet_id)
+    }
+
+    fn take_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+    ) -> Option<Option<Message>> {
+        let inner = self.inner.read();
+        let mut session = inner.sessions.get(client_id).unwrap().write();
+        let res = session.uncompleted_messages.remove(&packet_id);
+        if res.is_some() {
+            inner.record(|| Record::TakeUncompletedMessage {
+                client_id: client_id.to_string(),
+                packet_id,
+            });
+        }
+        res
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> StorageMetrics {
+        let inner = self.inner.read();
+        StorageMetrics {
+            session_count: inner.sessions.len(),
+            inflight_messages_count: inner
+                .sessions
+                .values()
+                .map(|session| session.read().inflight_pub_packets.len())
+                .sum::<usize>(),
+            retained_messages_count: inner.filter_tree.retained_messages_count(),
+            messages_count: inner.filter_tree.retained_messages_count()
+                + inner
+                    .sessions
+                    .values()
+                    .map(|session| session.read().queue.len())
+                    .sum::<usize>(),
+            messages_bytes: inner.filter_tree.retained_messages_bytes()
+                + inner
+                    .sessions
+                    .values()
+                    .map(|session| session.read().queue_bytes)
+                    .sum::<usize>(),
+            subscriptions_count: inner.filter_tree.subscriber_count(),
+            clients_expired: inner.clients_expired,
+        }
+    }
+
+    fn sessions(&self) -> Vec<SessionInfo> {
+        let inner = self.inner.read();
+        inner
+            .sessions
+            .iter()
+            .map(|(client_id, session)| session.read().info(client_id))
+            .collect()
+    }
+
+    fn session(&self, client_id: &str) -> Option<SessionInfo> {
+        let inner = self.inner.read();
+        let session = inner.sessions.get(client_id)?;
+        let info = session.read().info(client_id);
+        Some(info)
+    }
+
+    fn remove_session(&self, client_id: &str) -> bool {
+        let mut inner = self.inner.write();
+        if !inner.sessions.contains_key(client_id) {
+            return false;
+        }
+
+        inner.redispatch_shared_messages(client_id, false);
+        inner.record(|| Record::RemoveSession {
+            client_id: client_id.to_string(),
+        });
+        inner.remove_session(client_id);
+        true
+    }
+
+    fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        let inner = self.inner.read();
+        inner
+            .filter_tree
+            .subscriptions()
+            .into_iter()
+            .map(|(client_id, filter, filter_item)| {
+                subscription_info(client_id, filter, filter_item)
+            })
+            .collect()
+    }
+
+    fn client_subscriptions(&self, client_id: &str) -> Vec<SubscriptionInfo> {
+        let inner = self.inner.read();
+        inner
+            .filter_tree
+            .subscriptions()
+            .into_iter()
+            .filter(|(id, _, _)| *id == client_id)
+            .map(|(client_id, filter, filter_item)| {
+                subscription_info(client_id, filter, filter_item)
+            })
+            .collect()
+    }
+
+    fn retained_messages(&self) -> Vec<Message> {
+        let inner = self.inner.read();
+        inner
+            .filter_tree
+            .retained_messages()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    fn retained_message(&self, topic: &str) -> Option<Message> {
+        if !filter_util::valid_topic(topic) {
+            return None;
+        }
+        let inner = self.inner.read();
+        let msg = inner.filter_tree.matches_retained_messages(topic).next();
+        msg.cloned()
+    }
+}
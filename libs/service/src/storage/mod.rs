@@ -0,0 +1,203 @@
+mod disk;
+mod memory;
+mod shared;
+
+use std::num::{NonZeroU16, NonZeroUsize};
+use std::sync::Arc;
+
+use anyhow::Result;
+use codec::{LastWill, Publish, Qos, RetainHandling};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::config::{QueueLimits, SharedSubscriptionConfig, StorageConfig};
+use crate::filter_util::Filter;
+use crate::message::Message;
+use crate::state::ServiceMetrics;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+#[derive(Debug)]
+pub struct StorageMetrics {
+    pub session_count: usize,
+    pub inflight_messages_count: usize,
+    pub retained_messages_count: usize,
+    pub messages_count: usize,
+    pub messages_bytes: usize,
+    pub subscriptions_count: usize,
+    pub clients_expired: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FilterItem {
+    pub qos: Qos,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+    pub id: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct SessionInfo {
+    pub client_id: String,
+    pub connected: bool,
+    pub session_expiry_interval: u32,
+    pub queued_messages: usize,
+    pub queued_bytes: usize,
+    pub inflight_messages: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscriptionInfo {
+    pub client_id: String,
+    pub filter: String,
+    pub qos: Qos,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+    pub id: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Qos2State {
+    Published,
+    Recorded,
+}
+
+/// Storage for sessions, subscriptions, retained messages and inflight packets.
+#[allow(clippy::too_many_arguments)]
+pub trait StorageBackend: Send + Sync + 'static {
+    fn update_retained_message(&self, msg: Message);
+
+    fn create_session(
+        &self,
+        client_id: &str,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        queue_limits: QueueLimits,
+        last_will: Option<LastWill>,
+    ) -> (bool, Arc<Notify>);
+
+    fn disconnect_session(&self, client_id: &str, session_expiry_interval: u32);
+
+    fn update_sessions(&self);
+
+    fn subscribe(
+        &self,
+        client_id: &str,
+        filter: Filter<'_>,
+        qos: Qos,
+        no_local: bool,
+        retain_as_published: bool,
+        retain_handling: RetainHandling,
+        id: Option<NonZeroUsize>,
+    );
+
+    fn unsubscribe(&self, client_id: &str, filter: Filter<'_>) -> bool;
+
+    fn next_messages(&self, client_id: &str, limit: Option<usize>) -> Vec<Message>;
+
+    fn deliver(&self, msgs: Vec<Message>);
+
+    /// Dispatches a message of a shared subscription refused by the client to another member of
+    /// its share group.
+    ///
+    /// Returns `false` if every member has refused the message.
+    fn redispatch_refused_message(&self, client_id: &str, msg: Message) -> bool;
+
+    /// Adds a packet waiting for acknowledgement, `shared_msg` is the routed message if it was
+    /// queued through a shared subscription, it is dispatched again if the client leaves the
+    /// share group before acknowledging the packet.
+    fn add_inflight_pub_packet(
+        &self,
+        client_id: &str,
+        publish: Publish,
+        shared_msg: Option<Message>,
+    );
+
+    fn get_inflight_pub_packets(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        remove: bool,
+    ) -> Option<Publish>;
+
+    fn get_all_inflight_pub_packets(&self, client_id: &str) -> Vec<Publish>;
+
+    /// Returns `true` if a message was dropped because the queue is full and the overflow policy
+    /// is [`crate::config::OverflowPolicy::Disconnect`], and resets the flag.
+    fn take_queue_overflowed(&self, client_id: &str) -> bool;
+
+    fn get_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State>;
+
+    /// Marks an outgoing QoS 2 message as recorded by the receiver.
+    ///
+    /// Returns `false` if the message has not been published or has already been recorded.
+    fn set_qos2_recorded(&self, client_id: &str, packet_id: NonZeroU16) -> bool;
+
+    fn remove_qos2_state(&self, client_id: &str, packet_id: NonZeroU16) -> Option<Qos2State>;
+
+    /// Stores an incoming QoS 2 message until the sender releases it with PUBREL, `msg` is `None`
+    /// if the message is not routed, e.g. it was dropped by a plugin.
+    ///
+    /// Returns `false` if a message with the same packet identifier is already stored.
+    fn add_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+        msg: Option<Message>,
+    ) -> bool;
+
+    /// Returns `true` if an incoming QoS 2 message with the packet identifier is waiting for PUBREL.
+    fn has_uncompleted_message(&self, client_id: &str, packet_id: NonZeroU16) -> bool;
+
+    /// Removes an incoming QoS 2 message, returns `Some(None)` if the message is not routed.
+    fn take_uncompleted_message(
+        &self,
+        client_id: &str,
+        packet_id: NonZeroU16,
+    ) -> Option<Option<Message>>;
+
+    /// Persists the storage, it is called when the server shuts down.
+    fn flush(&self) -> Result<()>;
+
+    fn metrics(&self) -> StorageMetrics;
+
+    fn sessions(&self) -> Vec<SessionInfo>;
+
+    fn session(&self, client_id: &str) -> Option<SessionInfo>;
+
+    /// Removes a disconnected session, returns `false` if the session does not exist.
+    fn remove_session(&self, client_id: &str) -> bool;
+
+    fn subscriptions(&self) -> Vec<SubscriptionInfo>;
+
+    fn client_subscriptions(&self, client_id: &str) -> Vec<SubscriptionInfo>;
+
+    fn retained_messages(&self) -> Vec<Message>;
+
+    fn retained_message(&self, topic: &str) -> Option<Message>;
+}
+
+pub fn open(
+    config: &StorageConfig,
+    shared_subscriptions: SharedSubscriptionConfig,
+    service_metrics: Arc<ServiceMetrics>,
+) -> Result<Box<dyn StorageBackend>> {
+    Ok(match config {
+        StorageConfig::Memory => {
+            Box::new(MemoryStorage::new(shared_subscriptions, service_metrics))
+        }
+        StorageConfig::Disk {
+            path,
+            snapshot_interval,
+        } => Box::new(DiskStorage::open(
+            path,
+            *snapshot_interval,
+            shared_subscriptions,
+            service_metrics,
+        )?),
+    })
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use fnv::FnvHasher;
+use parking_lot::Mutex;
+
+use crate::config::{SharedSubscriptionConfig, SharedSubscriptionStrategy};
+use crate::message::Message;
+
+/// A member of a share group that matches a message.
+pub(super) struct Member<'a> {
+    pub client_id: &'a str,
+    pub online: bool,
+    /// Number of queued and inflight messages.
+    pub queued: usize,
+}
+
+#[derive(Default)]
+struct GroupState {
+    next: usize,
+    sticky: Option<String>,
+}
+
+/// Selects which member of a share group receives a message.
+#[derive(Default)]
+pub(super) struct SharedGroups {
+    config: SharedSubscriptionConfig,
+    states: Mutex<HashMap<String, GroupState>>,
+}
+
+#[inline]
+fn hash(value: impl Hash) -> usize {
+    let mut hasher = FnvHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+impl SharedGroups {
+    pub fn new(config: SharedSubscriptionConfig) -> Self {
+        Self {
+            config,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the index of the selected member, `members` must not be empty.
+    ///
+    /// Offline members are only selected if all the members are offline.
+    pub fn select(&self, share_name: &str, msg: &Message, members: &[Member<'_>]) -> usize {
+        assert!(!members.is_empty());
+
+        let mut candidates = (0..members.len())
+            .filter(|idx| members[*idx].online)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates.extend(0..members.len());
+        }
+
+        let strategy = self
+            .config
+            .groups
+            .get(share_name)
+            .copied()
+            .unwrap_or(self.config.strategy);
+
+        let n = match strategy {
+            SharedSubscriptionStrategy::Random => fastrand::usize(0..candidates.len()),
+            SharedSubscriptionStrategy::RoundRobin => {
+                let mut states = self.states.lock();
+                let state = states.entry(share_name.to_string()).or_default();
+                let n = state.next % candidates.len();
+                state.next = state.next.wrapping_add(1);
+                n
+            }
+            SharedSubscriptionStrategy::Sticky => {
+                let mut states = self.states.lock();
+                let state = states.entry(share_name.to_string()).or_default();
+                let current = state.sticky.as_deref().and_then(|client_id| {
+                    candidates
+                        .iter()
+                        .position(|idx| members[*idx].client_id == client_id)
+                });
+                match current {
+                    Some(n) => n,
+                    None => {
+                        let n = fastrand::usize(0..candidates.len());
+                        state.sticky = Some(members[candidates[n]].client_id.to_string());
+                        n
+                    }
+                }
+            }
+            SharedSubscriptionStrategy::HashClientId => {
+                hash(msg.from_client_id().map(|s| &**s).unwrap_or_default()) % candidates.len()
+            }
+            SharedSubscriptionStrategy::HashTopic => hash(&**msg.topic()) % candidates.len(),
+            SharedSubscriptionStrategy::LeastQueued => (0..candidates.len())
+                .min_by_key(|n| members[candidates[*n]].queued)
+                .unwrap(),
+        };
+
+        candidates[n]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Qos;
+
+    fn groups(strategy: SharedSubscriptionStrategy) -> SharedGroups {
+        SharedGroups::new(SharedSubscriptionConfig {
+            strategy,
+            groups: HashMap::new(),
+        })
+    }
+
+    fn member(client_id: &str, online: bool, queued: usize) -> Member<'_> {
+        Member {
+            client_id,
+            online,
+            queued,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let groups = groups(SharedSubscriptionStrategy::RoundRobin);
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+        let members = [
+            member("a", true, 0),
+            member("b", false, 0),
+            member("c", true, 0),
+        ];
+
+        assert_eq!(
+            (0..4)
+                .map(|_| groups.select("g", &msg, &members))
+                .collect::<Vec<_>>(),
+            vec![0, 2, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_skip_offline() {
+        let groups = groups(SharedSubscriptionStrategy::Random);
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+
+        let members = [member("a", false, 0), member("b", true, 0)];
+        for _ in 0..10 {
+            assert_eq!(groups.select("g", &msg, &members), 1);
+        }
+
+        let members = [member("a", false, 0)];
+        assert_eq!(groups.select("g", &msg, &members), 0);
+    }
+
+    #[test]
+    fn test_sticky() {
+        let groups = groups(SharedSubscriptionStrategy::Sticky);
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+        let members = [member("a", true, 0), member("b", true, 0)];
+
+        let first = groups.select("g", &msg, &members);
+        for _ in 0..10 {
+            assert_eq!(groups.select("g", &msg, &members), first);
+        }
+
+        let mut members = members;
+        members[first].online = false;
+        let second = groups.select("g", &msg, &members);
+        assert_ne!(second, first);
+
+        members[first].online = true;
+        assert_eq!(groups.select("g", &msg, &members), second);
+    }
+
+    #[test]
+    fn test_hash() {
+        let members = [
+            member("a", true, 0),
+            member("b", true, 0),
+            member("c", true, 0),
+        ];
+
+        let groups = self::groups(SharedSubscriptionStrategy::HashClientId);
+        let msg = Message::new("test", Qos::AtMostOnce, "1").with_from_client_id("x");
+        let n = groups.select("g", &msg, &members);
+        for i in 0..10 {
+            let msg =
+                Message::new(format!("test/{}", i), Qos::AtMostOnce, "1").with_from_client_id("x");
+            assert_eq!(groups.select("g", &msg, &members), n);
+        }
+
+        let groups = self::groups(SharedSubscriptionStrategy::HashTopic);
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+        let n = groups.select("g", &msg, &members);
+        for i in 0..10 {
+            let msg = Message::new("test", Qos::AtMostOnce, "1").with_from_client_id(i.to_string());
+            assert_eq!(groups.select("g", &msg, &members), n);
+        }
+    }
+
+    #[test]
+    fn test_least_queued() {
+        let groups = groups(SharedSubscriptionStrategy::LeastQueued);
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+        let members = [
+            member("a", true, 3),
+            member("b", false, 0),
+            member("c", true, 1),
+        ];
+        assert_eq!(groups.select("g", &msg, &members), 2);
+    }
+
+    #[test]
+    fn test_group_strategy() {
+        let groups = SharedGroups::new(SharedSubscriptionConfig {
+            strategy: SharedSubscriptionStrategy::Random,
+            groups: std::iter::once(("g".to_string(), SharedSubscriptionStrategy::LeastQueued))
+                .collect(),
+        });
+        let msg = Message::new("test", Qos::AtMostOnce, "1");
+        let members = [member("a", true, 1), member("b", true, 0)];
+        for _ in 0..10 {
+            assert_eq!(groups.select("g", &msg, &members), 1);
+        }
+    }
+}
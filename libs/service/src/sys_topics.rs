@@ -9,14 +9,12 @@ impl ServiceState {
 
         macro_rules! update {
             ($state:expr, $topic:literal, $payload:expr) => {
-                $state.storage.deliver(std::iter::once(
-                    Message::new(
-                        $topic,
-                        Qos::AtMostOnce,
-                        bytes::Bytes::from($payload.to_string().into_bytes()),
-                    )
-                    .with_retain(true),
-                ));
+                $state.storage.deliver(vec![Message::new(
+                    $topic,
+                    Qos::AtMostOnce,
+                    bytes::Bytes::from($payload.to_string().into_bytes()),
+                )
+                .with_retain(true)]);
             };
         }
 
@@ -151,17 +151,18 @@ impl Trie {
         matched.into_iter()
     }
 
+    /// Returns the members of each share group that match the topic, ordered by client id.
     pub fn matches_shared(
         &self,
         topic: impl AsRef<str>,
-    ) -> impl Iterator<Item = (&str, Vec<&FilterItem>)> {
+    ) -> impl Iterator<Item = (&str, IndexMap<&str, Vec<&FilterItem>>)> {
         let segments = topic.as_ref().split('/').collect::<Vec<_>>();
         assert!(!segments.is_empty());
 
         let mut nodes = Vec::new();
-        let mut matched: HashMap<&str, Vec<&FilterItem>> = HashMap::new();
+        let mut matched = Vec::new();
 
-        for node in self.share_subscriptions.values() {
+        for (share_name, node) in &self.share_subscriptions {
             let mut share_matches: IndexMap<&str, Vec<&FilterItem>> = IndexMap::new();
 
             nodes.clear();
@@ -171,10 +172,8 @@ impl Trie {
             }
 
             if !share_matches.is_empty() {
-                let (k, items) = share_matches
-                    .swap_remove_index(fastrand::usize(0..share_matches.len()))
-                    .unwrap();
-                matched.entry(k).or_default().extend(items);
+                share_matches.sort_keys();
+                matched.push((share_name.as_str(), share_matches));
             }
         }
 
@@ -397,6 +396,58 @@ impl Trie {
         res
     }
 
+    fn internal_subscriptions<'a>(
+        parent_node: &'a Node,
+        path: &mut Vec<&'a str>,
+        prefix: &str,
+        res: &mut Vec<(&'a str, String, &'a FilterItem)>,
+    ) {
+        if !path.is_empty() {
+            for (client_id, filter_item) in &parent_node.data {
+                res.push((
+                    client_id,
+                    format!("{}{}", prefix, path.join("/")),
+                    filter_item,
+                ));
+            }
+        }
+
+        let children = parent_node
+            .hash_child
+            .as_deref()
+            .map(|node| ("#", node))
+            .into_iter()
+            .chain(parent_node.plus_child.as_deref().map(|node| ("+", node)))
+            .chain(
+                parent_node
+                    .named_children
+                    .iter()
+                    .map(|(name, node)| (name.as_str(), node)),
+            );
+        for (segment, node) in children {
+            path.push(segment);
+            Self::internal_subscriptions(node, path, prefix, res);
+            path.pop();
+        }
+    }
+
+    /// Returns all subscriptions as `(client id, filter, filter item)`.
+    pub fn subscriptions(&self) -> Vec<(&str, String, &FilterItem)> {
+        let mut res = Vec::new();
+        Self::internal_subscriptions(&self.root, &mut Vec::new(), "", &mut res);
+        for (share_name, node) in &self.share_subscriptions {
+            let prefix = format!("$share/{}/", share_name);
+            Self::internal_subscriptions(node, &mut Vec::new(), &prefix, &mut res);
+        }
+        res
+    }
+
+    pub fn retained_messages(&self) -> Vec<&Message> {
+        let mut msgs = Vec::new();
+        Self::internal_matches_retained_messages_all(&self.root, &mut msgs);
+        msgs
+    }
+
     #[inline]
     pub fn subscriber_count(&self) -> usize {
         self.subscribers_count
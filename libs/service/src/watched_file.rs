@@ -0,0 +1,96 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use parking_lot::{Mutex, RwLock};
+
+/// A value parsed from a file, it is reloaded when the modified time of the file changes.
+///
+/// The previous value is kept if the changed file fails to parse.
+pub struct WatchedFile<T> {
+    filename: String,
+    parse: fn(&str) -> Result<T>,
+    value: RwLock<Arc<T>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl<T: Send + Sync + 'static> WatchedFile<T> {
+    /// Loads the file and checks it every `interval` until the returned value is dropped.
+    pub async fn open(
+        filename: impl Into<String>,
+        interval: Duration,
+        parse: fn(&str) -> Result<T>,
+    ) -> Result<Arc<Self>> {
+        let filename = filename.into();
+        let modified = modified_time(&filename).await;
+        let value = load(&filename, parse).await?;
+        let file = Arc::new(Self {
+            filename,
+            parse,
+            value: RwLock::new(Arc::new(value)),
+            modified: Mutex::new(modified),
+        });
+        tokio::spawn(Self::watch(Arc::downgrade(&file), interval));
+        Ok(file)
+    }
+
+    #[inline]
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().clone()
+    }
+
+    /// Reloads the file if it has changed since the last check.
+    pub async fn reload_if_changed(&self) {
+        let modified = modified_time(&self.filename).await;
+        {
+            let mut current = self.modified.lock();
+            if *current == modified {
+                return;
+            }
+            *current = modified;
+        }
+
+        match load(&self.filename, self.parse).await {
+            Ok(value) => {
+                *self.value.write() = Arc::new(value);
+                tracing::info!(file = %self.filename, "file reloaded");
+            }
+            Err(err) => {
+                tracing::error!(
+                    file = %self.filename,
+                    error = %format_args!("{:#}", err),
+                    "failed to reload file",
+                );
+            }
+        }
+    }
+
+    async fn watch(file: Weak<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match file.upgrade() {
+                Some(file) => file.reload_if_changed().await,
+                None => break,
+            }
+        }
+    }
+}
+
+async fn load<T>(filename: &str, parse: fn(&str) -> Result<T>) -> Result<T> {
+    let data = tokio::fs::read_to_string(filename)
+        .await
+        .with_context(|| format!("failed to read file: {}", filename))?;
+    parse(&data).with_context(|| format!("failed to parse file: {}", filename))
+}
+
+async fn modified_time(filename: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(filename)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
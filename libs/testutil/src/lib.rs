@@ -1,9 +1,11 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::default_trait_access)]
 
+mod plugin;
 mod runner;
 mod suite;
 
+pub use plugin::TestPlugin;
 pub use runner::run;
 pub use suite::Suite;
 
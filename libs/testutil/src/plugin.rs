@@ -0,0 +1,227 @@
+//! A plugin whose hooks are configured by the test suites.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use bytestring::ByteString;
+use codec::{PubAckReasonCode, Qos, SubscribeReasonCode};
+use serde::Deserialize;
+use serde_yaml::Value;
+use service::filter_util;
+use service::plugin::{
+    AclRequest, DeliveryInterceptResult, EnhancedAuth, EnhancedAuthResult, Plugin, PluginFactory,
+    PluginResult, PublishInterceptResult, SubscribeAuthorization,
+};
+use service::{Message, RemoteAddr};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Config {
+    /// The results of `intercept_publish`, the first rule whose filter matches the topic is used.
+    intercept_publish: Vec<PublishRule>,
+    /// The results of `authorize_subscribe`, the first rule with the same filter is used.
+    authorize_subscribe: Vec<SubscribeRule>,
+    /// The results of `intercept_delivery`, the first rule whose filter matches the topic and
+    /// whose client id is the subscriber is used.
+    intercept_delivery: Vec<DeliveryRule>,
+    /// A challenge-response exchange of `enhanced_auth`.
+    enhanced_auth: Option<ChallengeConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishRule {
+    filter: String,
+    #[serde(flatten)]
+    result: PublishResult,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+enum PublishResult {
+    Modify {
+        topic: Option<ByteString>,
+        payload: Option<String>,
+    },
+    Drop,
+    Reject {
+        reason_code: PubAckReasonCode,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeRule {
+    filter: String,
+    #[serde(flatten)]
+    result: SubscribeResult,
+    reason_string: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+enum SubscribeResult {
+    Grant { max_qos: Qos },
+    Refuse { reason_code: SubscribeReasonCode },
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveryRule {
+    filter: String,
+    client_id: Option<String>,
+    #[serde(flatten)]
+    result: DeliveryResult,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+enum DeliveryResult {
+    Modify {
+        topic: Option<ByteString>,
+        payload: Option<String>,
+    },
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ChallengeConfig {
+    method: String,
+    /// The data sent to the client after the first step.
+    challenge: String,
+    /// The data that the client must respond to the challenge to authenticate.
+    response: String,
+    uid: String,
+}
+
+struct Challenge {
+    config: ChallengeConfig,
+    challenged: bool,
+}
+
+#[async_trait::async_trait]
+impl EnhancedAuth for Challenge {
+    async fn step(&mut self, data: Option<Bytes>) -> PluginResult<EnhancedAuthResult> {
+        if !self.challenged {
+            self.challenged = true;
+            return Ok(EnhancedAuthResult::Continue(Bytes::from(
+                self.config.challenge.clone(),
+            )));
+        }
+        Ok(
+            if data.as_deref() == Some(self.config.response.as_bytes()) {
+                EnhancedAuthResult::Success {
+                    uid: self.config.uid.clone(),
+                    data: None,
+                }
+            } else {
+                EnhancedAuthResult::Failure
+            },
+        )
+    }
+}
+
+/// Creates the plugins of type `test`.
+pub struct TestPlugin;
+
+#[async_trait::async_trait]
+impl PluginFactory for TestPlugin {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    async fn create(&self, config: Value) -> PluginResult<Arc<dyn Plugin>> {
+        let config: Config = serde_yaml::from_value(config)?;
+        Ok(Arc::new(TestPluginImpl { config }))
+    }
+}
+
+struct TestPluginImpl {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for TestPluginImpl {
+    async fn enhanced_auth(
+        &self,
+        _remote_addr: &RemoteAddr,
+        _client_id: &str,
+        method: &str,
+    ) -> PluginResult<Option<Box<dyn EnhancedAuth>>> {
+        Ok(match &self.config.enhanced_auth {
+            Some(config) if config.method == method => Some(Box::new(Challenge {
+                config: config.clone(),
+                challenged: false,
+            })),
+            _ => None,
+        })
+    }
+
+    async fn intercept_publish(
+        &self,
+        _remote_addr: &RemoteAddr,
+        _client_id: Option<&str>,
+        _uid: Option<&str>,
+        msg: &Message,
+    ) -> PluginResult<PublishInterceptResult> {
+        let rule = self
+            .config
+            .intercept_publish
+            .iter()
+            .find(|rule| filter_util::matches_topic(&rule.filter, msg.topic()));
+        Ok(match rule.map(|rule| &rule.result) {
+            Some(PublishResult::Modify { topic, payload }) => PublishInterceptResult::Modified {
+                topic: topic.clone(),
+                payload: payload.clone().map(Bytes::from),
+                properties: None,
+            },
+            Some(PublishResult::Drop) => PublishInterceptResult::Drop,
+            Some(PublishResult::Reject { reason_code }) => {
+                PublishInterceptResult::Reject(*reason_code)
+            }
+            None => PublishInterceptResult::Continue,
+        })
+    }
+
+    async fn authorize_subscribe(
+        &self,
+        request: &AclRequest<'_>,
+    ) -> PluginResult<SubscribeAuthorization> {
+        let rule = match self
+            .config
+            .authorize_subscribe
+            .iter()
+            .find(|rule| rule.filter == request.topic)
+        {
+            Some(rule) => rule,
+            None => return Ok(SubscribeAuthorization::granted(Qos::ExactlyOnce)),
+        };
+        let authorization = match rule.result {
+            SubscribeResult::Grant { max_qos } => SubscribeAuthorization::granted(max_qos),
+            SubscribeResult::Refuse { reason_code } => SubscribeAuthorization::refused(reason_code),
+        };
+        Ok(match &rule.reason_string {
+            Some(reason_string) => authorization.with_reason_string(reason_string),
+            None => authorization,
+        })
+    }
+
+    async fn intercept_delivery(
+        &self,
+        _remote_addr: &RemoteAddr,
+        client_id: &str,
+        _uid: Option<&str>,
+        msg: &Message,
+    ) -> PluginResult<DeliveryInterceptResult> {
+        let rule = self.config.intercept_delivery.iter().find(|rule| {
+            filter_util::matches_topic(&rule.filter, msg.topic())
+                && (rule.client_id.is_none() || rule.client_id.as_deref() == Some(client_id))
+        });
+        Ok(match rule.map(|rule| &rule.result) {
+            Some(DeliveryResult::Modify { topic, payload }) => DeliveryInterceptResult::Modified {
+                topic: topic.clone(),
+                payload: payload.clone().map(Bytes::from),
+                properties: None,
+            },
+            Some(DeliveryResult::Drop) => DeliveryInterceptResult::Drop,
+            None => DeliveryInterceptResult::Continue,
+        })
+    }
+}
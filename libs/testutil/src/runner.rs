@@ -16,6 +16,7 @@ use crate::suite::{Step, Suite};
 
 struct RunnerContext {
     state: Arc<ServiceState>,
+    plugins: Vec<(&'static str, Arc<dyn Plugin>)>,
     clients: HashMap<ByteString, Codec<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>>,
 }
 
@@ -25,9 +26,10 @@ where
     F: Future<Output = Vec<(&'static str, Arc<dyn Plugin>)>>,
 {
     let plugins = create_plugins(suite.plugins).await;
-    let state = ServiceState::new(suite.config, plugins).unwrap();
+    let state = ServiceState::new(suite.config, plugins.clone()).unwrap();
     let ctx = Arc::new(Mutex::new(RunnerContext {
         state,
+        plugins,
         clients: HashMap::new(),
     }));
 
@@ -52,8 +54,13 @@ fn execute_step(
                 let (client_reader, client_writer) = tokio::io::split(client);
                 let codec = Codec::new(client_reader, client_writer);
                 let remote_addr = remote_addr.unwrap_or_else(|| RemoteAddr {
+                    listener: "memory".into(),
                     protocol: "memory".into(),
                     addr: Some(format!("{}", id).into()),
+                    mountpoint: None,
+                    cert_identity: None,
+                    peer_cred: None,
+                    uid: None,
                 });
                 tokio::spawn(client_loop(
                     ctx.state.clone(),
@@ -138,6 +145,17 @@ fn execute_step(
                     panic!("connection is still not closed.")
                 }
             }
+            Step::Reload { config } => {
+                // println!("[RELOAD]");
+                let ctx = ctx.lock().await;
+                ctx.state.reload(config, ctx.plugins.clone()).unwrap();
+            }
+            Step::Shutdown => {
+                // println!("[SHUTDOWN]");
+                let state = ctx.lock().await.state.clone();
+                let drain_period = Duration::from_secs(state.config().shutdown_drain_period);
+                state.shutdown(drain_period).await.unwrap();
+            }
             Step::Delay { duration } => {
                 // println!("[DELAY] duration={}", duration);
                 tokio::time::sleep(Duration::from_secs(duration)).await
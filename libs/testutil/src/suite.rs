@@ -21,6 +21,13 @@ pub enum Step {
         after: Option<u64>,
     },
     Eof,
+    /// Reloads the service config, the plugins are kept.
+    Reload {
+        #[serde(default)]
+        config: ServiceConfig,
+    },
+    /// Shuts down the service with the drain period of the config.
+    Shutdown,
     Delay {
         duration: u64,
     },